@@ -0,0 +1,297 @@
+//! コマンドライン引数の解析。
+
+use ime_watcher::config::ConfigLayer;
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: ime-watch [--config <path>] [--backend auto|fcitx5|ibus] [--format text|json]
+                 [--dbus-timeout <ms>] [--process-interval <ms>] [--sni-id <id>]
+                 [--emit change|all|heartbeat] [--heartbeat-secs <secs>]
+                 [--debounce-quiet <ms>] [--debounce-max-wait <ms>]
+                 [--socket <path>] [--hooks <path>] <command>
+
+commands:
+    watch   print the input method every time it changes and run the hooks
+            (default: $XDG_CONFIG_HOME/ime-watcher/hooks.toml)
+    get     print the current input method and exit
+    list    print the available engines
+    groups  print the input method groups and their input methods (fcitx5)
+    doctor  explain which backend is used and why
+    daemon  serve the input method on a Unix socket (default: $XDG_RUNTIME_DIR/ime-watcher.sock)
+            and as org.imewatcher.Watcher1 on the session bus, and run the hooks
+    set <engine>
+            switch to the engine
+    on      turn the input method on (ibus: switch to the last composing engine)
+    off     turn the input method off (ibus: switch to the last direct engine)
+    toggle  switch between on and off
+    print-config
+            print the effective configuration merged from the config file
+            (default: $XDG_CONFIG_HOME/ime-watcher/config.toml), IME_WATCHER_* and the options";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Watch,
+    Get,
+    List,
+    Groups,
+    Doctor,
+    Daemon,
+    Set(String),
+    On,
+    Off,
+    Toggle,
+    PrintConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub command: Command,
+    /// `--config`
+    pub config: Option<PathBuf>,
+    /// 設定ファイルと環境変数より優先する設定
+    pub overrides: ConfigLayer,
+    /// `--socket`
+    pub socket: Option<PathBuf>,
+    /// `--hooks`
+    pub hooks: Option<PathBuf>,
+}
+
+/// 解析に失敗した理由。`Help`の場合は使い方を表示して正常終了する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    Help,
+    Invalid(String),
+}
+
+impl std::fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgsError::Help => write!(f, "{USAGE}"),
+            ArgsError::Invalid(reason) => write!(f, "{reason}\n\n{USAGE}"),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+impl Args {
+    /// オプションはサブコマンドの前後どちらにも置ける。`--backend=ibus`の形式も受け付ける
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ArgsError> {
+        let mut command = None;
+        let mut config = None;
+        let mut overrides = ConfigLayer::default();
+        let mut socket = None;
+        let mut hooks = None;
+        // `set`の後でエンジンをまだ受け取っていない
+        let mut expects_engine = false;
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (key, inline_value) = match arg.split_once('=') {
+                Some((key, value)) if key.starts_with("--") => {
+                    (key.to_owned(), Some(value.to_owned()))
+                }
+                _ => (arg, None),
+            };
+
+            let mut value = |key: &str| {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ArgsError::Invalid(format!("{key} requires a value")))
+            };
+
+            match key.as_str() {
+                "-h" | "--help" => return Err(ArgsError::Help),
+                "--config" => config = Some(PathBuf::from(value("--config")?)),
+                "--backend" => {
+                    overrides.backend =
+                        Some(value("--backend")?.parse().map_err(ArgsError::Invalid)?);
+                }
+                "--format" => {
+                    overrides.format =
+                        Some(value("--format")?.parse().map_err(ArgsError::Invalid)?);
+                }
+                "--dbus-timeout" => overrides.dbus_timeout_ms = Some(number(value(&key)?, &key)?),
+                "--process-interval" => {
+                    overrides.process_interval_ms = Some(number(value(&key)?, &key)?);
+                }
+                "--sni-id" => overrides.sni_id = Some(value("--sni-id")?),
+                "--emit" => {
+                    overrides.emit = Some(value("--emit")?.parse().map_err(ArgsError::Invalid)?);
+                }
+                "--heartbeat-secs" => {
+                    overrides.heartbeat_secs = Some(number(value(&key)?, &key)?);
+                }
+                "--debounce-quiet" => {
+                    overrides.debounce_quiet_ms = Some(number(value(&key)?, &key)?);
+                }
+                "--debounce-max-wait" => {
+                    overrides.debounce_max_wait_ms = Some(number(value(&key)?, &key)?);
+                }
+                "--socket" => socket = Some(PathBuf::from(value("--socket")?)),
+                "--hooks" => hooks = Some(PathBuf::from(value("--hooks")?)),
+                other if other.starts_with('-') => {
+                    return Err(ArgsError::Invalid(format!("unknown option `{other}`")));
+                }
+                other if expects_engine => {
+                    command = Some(Command::Set(other.to_owned()));
+                    expects_engine = false;
+                }
+                other => {
+                    let parsed = match other {
+                        "watch" => Command::Watch,
+                        "get" => Command::Get,
+                        "list" => Command::List,
+                        "groups" => Command::Groups,
+                        "doctor" => Command::Doctor,
+                        "daemon" => Command::Daemon,
+                        // エンジンは次のオプションでない引数
+                        "set" => {
+                            expects_engine = true;
+                            Command::Set(String::new())
+                        }
+                        "on" => Command::On,
+                        "off" => Command::Off,
+                        "toggle" => Command::Toggle,
+                        "print-config" => Command::PrintConfig,
+                        other => {
+                            return Err(ArgsError::Invalid(format!("unknown command `{other}`")));
+                        }
+                    };
+
+                    if command.replace(parsed).is_some() {
+                        return Err(ArgsError::Invalid(
+                            "only one command can be given".to_owned(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if expects_engine {
+            return Err(ArgsError::Invalid("set requires an engine".to_owned()));
+        }
+
+        Ok(Self {
+            command: command.ok_or_else(|| ArgsError::Invalid("no command is given".to_owned()))?,
+            config,
+            overrides,
+            socket,
+            hooks,
+        })
+    }
+}
+
+/// 時間の値。範囲の検証は設定を重ねた後に行う
+fn number(value: String, key: &str) -> Result<u64, ArgsError> {
+    value
+        .parse()
+        .map_err(|e| ArgsError::Invalid(format!("invalid {key} `{value}`: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ime_watcher::change::EmitMode;
+    use ime_watcher::config::{BackendChoice, OutputFormat};
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_options_override_nothing() {
+        assert_eq!(
+            parse(&["watch"]).unwrap(),
+            Args {
+                command: Command::Watch,
+                config: None,
+                overrides: ConfigLayer::default(),
+                socket: None,
+                hooks: None,
+            }
+        );
+    }
+
+    #[test]
+    fn options_around_command() {
+        assert_eq!(
+            parse(&[
+                "--backend",
+                "ibus",
+                "daemon",
+                "--format=json",
+                "--socket",
+                "/tmp/s",
+                "--dbus-timeout=1500",
+                "--emit",
+                "heartbeat",
+                "--config",
+                "/tmp/config.toml",
+            ])
+            .unwrap(),
+            Args {
+                command: Command::Daemon,
+                config: Some(PathBuf::from("/tmp/config.toml")),
+                overrides: ConfigLayer {
+                    backend: Some(BackendChoice::Ibus),
+                    format: Some(OutputFormat::Json),
+                    dbus_timeout_ms: Some(1500),
+                    emit: Some(EmitMode::Heartbeat),
+                    ..Default::default()
+                },
+                socket: Some(PathBuf::from("/tmp/s")),
+                hooks: None,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(matches!(parse(&[]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(
+            parse(&["watch", "get"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&["--backend", "uim", "watch"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&["watch", "--format"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&["watch", "--process-interval", "1s"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(parse(&["set"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(
+            parse(&["set", "--backend", "ibus"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert_eq!(parse(&["list", "--help"]), Err(ArgsError::Help));
+    }
+
+    #[test]
+    fn set_takes_an_engine() {
+        assert_eq!(
+            parse(&["set", "mozc", "--backend", "fcitx5"])
+                .unwrap()
+                .command,
+            Command::Set("mozc".to_owned())
+        );
+        // オプションをエンジンとして受け取らない
+        let args = parse(&["set", "--backend", "ibus", "mozc"]).unwrap();
+        assert_eq!(args.command, Command::Set("mozc".to_owned()));
+        assert_eq!(args.overrides.backend, Some(BackendChoice::Ibus));
+        assert_eq!(
+            parse(&["set", "--backend=ibus", "mozc"]).unwrap().command,
+            Command::Set("mozc".to_owned())
+        );
+        assert_eq!(parse(&["off"]).unwrap().command, Command::Off);
+    }
+}
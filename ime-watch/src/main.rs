@@ -0,0 +1,414 @@
+mod args;
+mod output;
+
+use ime_watcher::app_memory::AppMemory;
+use ime_watcher::config::{BackendChoice, Config, OutputFormat};
+use ime_watcher::hooks::{Hooks, HooksError};
+use ime_watcher::linux::ibus::address::AddressLookup;
+use ime_watcher::linux::{
+    Backend, DbusSettings, Fcitx5Watcher, IbusWatcher, ImeWatchError, StateService,
+    detect::Evidence,
+    fcitx5,
+    fcitx5::Fcitx5Controller,
+    focus, ibus,
+    ibus::{EngineHistory, IbusController},
+    session_channel,
+};
+use ime_watcher::socket::{StateServer, default_socket_path};
+use ime_watcher::{EngineClassifier, ImeEvent, ImeWatcher, InputKind};
+
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use args::{Args, ArgsError, Command};
+use output::{JsonLines, JsonObject, event_text, format_state};
+
+/// `get`で最初の状態を待つ時間
+const GET_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Watcher = Box<dyn ImeWatcher<Error = ImeWatchError>>;
+
+fn resolve_backend(config: &Config) -> Result<Backend, ImeWatchError> {
+    match config.backend {
+        BackendChoice::Fcitx5 => Ok(Backend::Fcitx5),
+        BackendChoice::Ibus => Ok(Backend::Ibus),
+        BackendChoice::Auto => Ok(Evidence::collect(&config.into()).decide()?.backend),
+    }
+}
+
+fn new_watcher(backend: Backend, config: &Config, classifier: EngineClassifier) -> Watcher {
+    let settings = DbusSettings::from(config);
+
+    match backend {
+        Backend::Fcitx5 => Box::new(
+            Fcitx5Watcher::new()
+                .with_classifier(classifier)
+                .with_settings(settings),
+        ),
+        Backend::Ibus => Box::new(
+            IbusWatcher::new()
+                .with_classifier(classifier)
+                .with_settings(settings),
+        ),
+    }
+}
+
+/// `--hooks`、なければユーザーの設定ファイルのフック
+fn load_hooks(args: &Args) -> Result<Hooks, HooksError> {
+    match &args.hooks {
+        Some(path) => Hooks::from_file(path),
+        None => Hooks::from_user_config(),
+    }
+}
+
+/// 設定されていれば、フォーカスに合わせてエンジンを切り替えるスレッドを起動する。
+fn spawn_app_memory(
+    backend: Backend,
+    config: &Config,
+    watcher: &Watcher,
+) -> Option<JoinHandle<()>> {
+    if !config.app_memory.is_enabled() {
+        return None;
+    }
+
+    let settings = DbusSettings::from(config);
+    let memory = AppMemory::new(config.app_memory.clone());
+    let receiver = watcher.subscribe();
+
+    Some(std::thread::spawn(move || {
+        let report = |e: &dyn std::error::Error| eprintln!("app memory: {e}");
+
+        if let Err(e) = focus::run_app_memory(backend, &settings, memory, &receiver, report) {
+            report(&e);
+        }
+    }))
+}
+
+/// 停止されるまでイベントを出力する。
+fn watch(
+    args: &Args,
+    config: &Config,
+    classifier: EngineClassifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut hooks = load_hooks(args)?;
+    let backend = resolve_backend(config)?;
+    let mut watcher = new_watcher(backend, config, classifier);
+
+    let receiver = watcher.subscribe();
+    let app_memory_thread = spawn_app_memory(backend, config, &watcher);
+
+    // SIGINT/SIGTERMで停止する
+    let stop_handle = watcher.stop_handle();
+    ctrlc::set_handler(move || stop_handle.stop())?;
+
+    watcher.start()?;
+
+    let mut json_lines = JsonLines::new(backend);
+
+    for event in receiver {
+        let line = match config.format {
+            OutputFormat::Text => event_text(&event),
+            OutputFormat::Json => json_lines.line(&event),
+        };
+
+        println!("{line}");
+
+        hooks.handle(&event);
+    }
+
+    watcher.stop()?;
+
+    if let Some(thread) = app_memory_thread {
+        thread.join().expect("app memory thread panicked");
+    }
+
+    Ok(())
+}
+
+/// 現在の状態を一度だけ出力する。
+fn get(config: &Config, classifier: EngineClassifier) -> Result<(), Box<dyn std::error::Error>> {
+    let mut watcher = new_watcher(resolve_backend(config)?, config, classifier);
+
+    let receiver = watcher.subscribe();
+    watcher.start()?;
+
+    // 購読直後に現在の状態が送られる
+    let state = loop {
+        match receiver.recv_timeout(GET_TIMEOUT) {
+            Ok(ImeEvent::Changed(state)) => break Some(state),
+            Ok(_) => continue,
+            Err(_) => break None,
+        }
+    };
+
+    watcher.stop()?;
+
+    let state = state.ok_or("failed to get the current input method")?;
+    println!("{}", format_state(config.format, &state));
+
+    Ok(())
+}
+
+/// 利用可能なエンジンを出力する。
+fn list(config: &Config, classifier: EngineClassifier) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = DbusSettings::from(config);
+
+    let engines = match resolve_backend(config)? {
+        Backend::Fcitx5 => fcitx5::available_engines(&classifier, &settings)?,
+        Backend::Ibus => ibus::available_engines(&classifier, &settings)?,
+    };
+
+    for state in &engines {
+        println!("{}", format_state(config.format, state));
+    }
+
+    Ok(())
+}
+
+/// fcitx5のグループとその入力メソッドを出力する。
+fn groups(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if resolve_backend(config)? != Backend::Fcitx5 {
+        return Err("input method groups are only available on fcitx5".into());
+    }
+
+    let controller = Fcitx5Controller::connect(&config.into())?;
+    let current = controller.current_group()?;
+
+    for group in controller.groups()? {
+        let is_current = group.name == current;
+
+        if config.format == OutputFormat::Text {
+            let mark = if is_current { " (current)" } else { "" };
+            println!("{}{mark}", group.name);
+        }
+
+        for im in &group.input_methods {
+            // 空のレイアウトはグループの既定
+            let layout = match im.layout.as_str() {
+                "" => &group.default_layout,
+                layout => layout,
+            };
+
+            match config.format {
+                OutputFormat::Text => {
+                    let configurable = if im.configurable {
+                        "\tconfigurable"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "  {}\t{}\t{}\t{layout}{configurable}",
+                        im.unique_name, im.display_name, im.language_code,
+                    );
+                }
+                OutputFormat::Json => {
+                    let object = JsonObject::new()
+                        .string("group", &group.name)
+                        .raw("current", is_current.to_string())
+                        .string("unique_name", &im.unique_name)
+                        .string("display_name", &im.display_name)
+                        .string("language_code", &im.language_code)
+                        .string("layout", layout)
+                        .raw("configurable", im.configurable.to_string());
+
+                    println!("{}", object.build());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 1つの監視をソケットと`org.imewatcher.Watcher1`で共有する。停止されるまで戻らない
+fn daemon(
+    args: &Args,
+    config: &Config,
+    classifier: EngineClassifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match &args.socket {
+        Some(path) => path.clone(),
+        None => default_socket_path().ok_or("XDG_RUNTIME_DIR is not set, use --socket")?,
+    };
+
+    let mut hooks = load_hooks(args)?;
+    let server = StateServer::bind(&path)?;
+    let service = StateService::register(session_channel()?)?;
+    let backend = resolve_backend(config)?;
+    let mut watcher = new_watcher(backend, config, classifier);
+
+    let receiver = watcher.subscribe();
+    let app_memory_thread = spawn_app_memory(backend, config, &watcher);
+    // 公開に失敗してもソケットでの配信は続ける。失敗はその時点で表示する
+    let service_thread = std::thread::spawn({
+        let receiver = watcher.subscribe();
+        move || {
+            if let Err(e) = service.run(receiver) {
+                eprintln!("D-Bus service: {e}");
+            }
+        }
+    });
+    let hooks_thread = std::thread::spawn({
+        let receiver = watcher.subscribe();
+        move || {
+            for event in receiver {
+                hooks.handle(&event);
+            }
+        }
+    });
+
+    // SIGINT/SIGTERMで停止する。監視が止まるとサーバーも終了し、ソケットが削除される
+    let stop_handle = watcher.stop_handle();
+    ctrlc::set_handler(move || stop_handle.stop())?;
+
+    watcher.start()?;
+    eprintln!("serving on {}", server.path().display());
+
+    server.run(receiver)?;
+    watcher.stop()?;
+
+    hooks_thread.join().expect("hooks thread panicked");
+    if let Some(thread) = app_memory_thread {
+        thread.join().expect("app memory thread panicked");
+    }
+    service_thread
+        .join()
+        .expect("D-Bus service thread panicked");
+
+    Ok(())
+}
+
+/// 入力メソッドを切り替える。
+fn switch(
+    command: &Command,
+    config: &Config,
+    classifier: EngineClassifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match resolve_backend(config)? {
+        Backend::Fcitx5 => {
+            let controller = Fcitx5Controller::connect(&config.into())?;
+
+            match command {
+                Command::Set(engine) => controller.set_current_im(engine)?,
+                Command::On => controller.activate()?,
+                Command::Off => controller.deactivate()?,
+                Command::Toggle => controller.toggle()?,
+                _ => unreachable!("not a switching command"),
+            }
+        }
+        Backend::Ibus => {
+            // `on`/`off`の切り替え先は前回までの実行で使ったエンジン
+            let history_path = EngineHistory::default_path();
+            let history = history_path
+                .as_deref()
+                .map(EngineHistory::load)
+                .unwrap_or_default();
+
+            let mut controller = IbusController::connect(&config.into())?
+                .with_classifier(classifier)
+                .with_history(history);
+
+            match command {
+                Command::Set(engine) => controller.set_engine(engine)?,
+                Command::On => controller.switch_to(InputKind::Composing)?,
+                Command::Off => controller.switch_to(InputKind::Direct)?,
+                Command::Toggle => controller.toggle()?,
+                _ => unreachable!("not a switching command"),
+            }
+
+            if let Some(path) = history_path {
+                controller.history().save(path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 判定の材料と結果を出力する。バックエンドが見つからない場合は失敗とする
+fn doctor(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let evidence = Evidence::collect(&config.into());
+    let ibus_address = AddressLookup::from_env().resolve();
+    let detection = evidence.decide();
+
+    match config.format {
+        OutputFormat::Text => {
+            let or_unset = |value: &Option<String>| value.clone().unwrap_or("(unset)".to_owned());
+
+            println!("GTK_IM_MODULE: {}", or_unset(&evidence.gtk_im_module));
+            println!("QT_IM_MODULE: {}", or_unset(&evidence.qt_im_module));
+            println!("XMODIFIERS: {}", or_unset(&evidence.xmodifiers));
+            println!("session bus: {}", evidence.session_bus);
+            println!("org.fcitx.Fcitx5 owned: {}", evidence.fcitx5_owned);
+            println!("org.freedesktop.IBus owned: {}", evidence.ibus_owned);
+            match &ibus_address {
+                Ok(address) => println!("ibus address: {address}"),
+                Err(e) => println!("ibus address: {e}"),
+            }
+            match &detection {
+                Ok(detection) => println!("backend: {detection}"),
+                Err(e) => println!("backend: {e}"),
+            }
+        }
+        OutputFormat::Json => {
+            let object = JsonObject::new()
+                .optional_string("gtk_im_module", evidence.gtk_im_module.as_deref())
+                .optional_string("qt_im_module", evidence.qt_im_module.as_deref())
+                .optional_string("xmodifiers", evidence.xmodifiers.as_deref())
+                .raw("session_bus", evidence.session_bus.to_string())
+                .raw("fcitx5_owned", evidence.fcitx5_owned.to_string())
+                .raw("ibus_owned", evidence.ibus_owned.to_string())
+                .optional_string("ibus_address", ibus_address.as_deref().ok());
+
+            let object = match &detection {
+                Ok(detection) => object
+                    .string("backend", &detection.backend.to_string())
+                    .string("reason", &detection.reasons.join(", ")),
+                Err(e) => object
+                    .optional_string("backend", None)
+                    .string("reason", &e.to_string()),
+            };
+
+            println!("{}", object.build());
+        }
+    }
+
+    detection?;
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(ArgsError::Help) => {
+            println!("{}", args::USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let config = Config::load(args.config.as_deref(), args.overrides.clone())?;
+
+    // `print-config`と`doctor`はエンジンの判定を読み込まない
+    let classifier = EngineClassifier::from_user_config;
+
+    match args.command {
+        Command::Watch => watch(&args, &config, classifier()?),
+        Command::Get => get(&config, classifier()?),
+        Command::List => list(&config, classifier()?),
+        Command::Groups => groups(&config),
+        Command::Doctor => doctor(&config),
+        Command::Daemon => daemon(&args, &config, classifier()?),
+        Command::Set(_) | Command::On | Command::Off | Command::Toggle => {
+            switch(&args.command, &config, classifier()?)
+        }
+        Command::PrintConfig => {
+            print!("{}", config.to_toml());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! `--format`に応じた出力。JSONは1行に1オブジェクトとする。
+
+use ime_watcher::config::OutputFormat;
+use ime_watcher::linux::Backend;
+use ime_watcher::{ImeEvent, ImeState};
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// JSONの文字列リテラル
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+fn json_optional_string(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_owned(), json_string)
+}
+
+/// `"key":value`を並べたJSONのオブジェクトを組み立てる。
+#[derive(Default)]
+pub struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `value`はエンコード済みのJSONの値
+    pub fn raw(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.fields
+            .push(format!("{}:{}", json_string(key), value.into()));
+        self
+    }
+
+    pub fn string(self, key: &str, value: &str) -> Self {
+        self.raw(key, json_string(value))
+    }
+
+    pub fn optional_string(self, key: &str, value: Option<&str>) -> Self {
+        self.raw(key, json_optional_string(value))
+    }
+
+    pub fn optional_bool(self, key: &str, value: Option<bool>) -> Self {
+        self.raw(
+            key,
+            value.map_or_else(|| "null".to_owned(), |value| value.to_string()),
+        )
+    }
+
+    /// 状態の各フィールドを加える。
+    pub fn state(self, state: &ImeState) -> Self {
+        self.string("engine", &state.engine)
+            .optional_string("display_name", state.display_name.as_deref())
+            .optional_string("language", state.language.as_deref())
+            .string("kind", &state.kind.to_string())
+            .optional_string("symbol", state.symbol.as_deref())
+            .optional_bool("open", state.open)
+            .raw("active", state.is_active().to_string())
+    }
+
+    pub fn build(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+/// 状態を1行で表す。
+pub fn format_state(format: OutputFormat, state: &ImeState) -> String {
+    match format {
+        OutputFormat::Text => match &state.display_name {
+            Some(display_name) => format!("{state} {display_name}"),
+            None => state.to_string(),
+        },
+        OutputFormat::Json => JsonObject::new().state(state).build(),
+    }
+}
+
+/// `watch --format text`の出力
+pub fn event_text(event: &ImeEvent) -> String {
+    match event {
+        ImeEvent::Changed(state) => state.to_string(),
+        ImeEvent::BackendLost => "backend lost".to_owned(),
+        ImeEvent::BackendRecovered => "backend recovered".to_owned(),
+        ImeEvent::GroupChanged(group) => format!("group changed: {group}"),
+    }
+}
+
+/// UNIX時間(ミリ秒)をRFC 3339のUTCの時刻にする。
+pub fn rfc3339_utc(unix_ms: u64) -> String {
+    let secs = unix_ms / 1000;
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+
+    // 1970-01-01からの日数 -> 年月日 (Howard Hinnantのcivil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        unix_ms % 1000,
+    )
+}
+
+/// `watch --format json`の出力。変更ごとに時刻と直前のエンジンを加えた1行を返す
+pub struct JsonLines {
+    backend: Backend,
+    started: Instant,
+    previous: Option<ImeState>,
+}
+
+impl JsonLines {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            started: Instant::now(),
+            previous: None,
+        }
+    }
+
+    pub fn line(&mut self, event: &ImeEvent) -> String {
+        let unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        self.line_at(event, unix_ms, self.started.elapsed())
+    }
+
+    /// `unix_ms`は壁時計、`monotonic`は監視の開始からの経過時間
+    fn line_at(&mut self, event: &ImeEvent, unix_ms: u64, monotonic: Duration) -> String {
+        let object = JsonObject::new()
+            .string("time", &rfc3339_utc(unix_ms))
+            .raw("unix_ms", unix_ms.to_string())
+            .raw("monotonic_ms", monotonic.as_millis().to_string())
+            .string("backend", &self.backend.to_string());
+
+        let previous_engine = self.previous.as_ref().map(|state| state.engine.as_str());
+
+        let object = match event {
+            ImeEvent::Changed(state) => object
+                .string("event", "changed")
+                .optional_string("previous_engine", previous_engine)
+                .state(state),
+            ImeEvent::BackendLost => object
+                .string("event", "backend_lost")
+                .optional_string("previous_engine", previous_engine),
+            ImeEvent::BackendRecovered => object
+                .string("event", "backend_recovered")
+                .optional_string("previous_engine", previous_engine),
+            ImeEvent::GroupChanged(group) => object
+                .string("event", "group_changed")
+                .optional_string("previous_engine", previous_engine)
+                .string("group", group),
+        };
+
+        if let ImeEvent::Changed(state) = event {
+            self.previous = Some(state.clone());
+        }
+
+        object.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ime_watcher::InputKind;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\n""#);
+        assert_eq!(json_string("あ\u{1}"), "\"あ\\u0001\"");
+    }
+
+    #[test]
+    fn formats_utc_time() {
+        assert_eq!(rfc3339_utc(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339_utc(951_782_400_123), "2000-02-29T00:00:00.123Z");
+        assert_eq!(rfc3339_utc(1_792_215_296_007), "2026-10-17T05:34:56.007Z");
+    }
+
+    #[test]
+    fn json_lines_track_previous_engine() {
+        let mut lines = JsonLines::new(Backend::Fcitx5);
+
+        let us = ImeState::new("keyboard-us").with_kind(InputKind::Direct);
+        let mozc = ImeState::new("mozc").with_kind(InputKind::Composing);
+
+        let first = lines.line_at(&ImeEvent::Changed(us), 0, Duration::from_millis(5));
+        assert!(first.starts_with(
+            r#"{"time":"1970-01-01T00:00:00.000Z","unix_ms":0,"monotonic_ms":5,"backend":"fcitx5","event":"changed","previous_engine":null,"engine":"keyboard-us","#
+        ));
+
+        let second = lines.line_at(&ImeEvent::Changed(mozc), 1000, Duration::from_millis(1005));
+        assert!(second.contains(r#""previous_engine":"keyboard-us","engine":"mozc","#));
+
+        let lost = lines.line_at(&ImeEvent::BackendLost, 2000, Duration::from_millis(2005));
+        assert!(lost.ends_with(r#""event":"backend_lost","previous_engine":"mozc"}"#));
+
+        let group = ImeEvent::GroupChanged("Japanese".to_owned());
+        let group = lines.line_at(&group, 3000, Duration::from_millis(3005));
+        assert!(
+            group.ends_with(
+                r#""event":"group_changed","previous_engine":"mozc","group":"Japanese"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn state_as_json() {
+        let state = ImeState::new("mozc-jp")
+            .with_kind(InputKind::Composing)
+            .with_language("ja")
+            .with_open(true);
+
+        assert_eq!(
+            format_state(OutputFormat::Json, &state),
+            r#"{"engine":"mozc-jp","display_name":null,"language":"ja","kind":"composing","symbol":null,"open":true,"active":true}"#
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! アプリケーションごとの入力メソッドの記憶。
+//!
+//! フォーカスされているアプリケーションごとに最後のエンジンを覚え、フォーカスが移ったときに
+//! 覚えたエンジンか設定の規則のエンジンに切り替える。フォーカスの取得と切り替えの方法は
+//! バックエンドごとに異なるため、[`AppMemory::run`]に関数として渡す。
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::{ImeEvent, ImeState};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppMemorySettings {
+    /// フォーカスが戻ったときに最後のエンジンに戻すか
+    pub restore: bool,
+    /// フォーカスを確認する間隔
+    pub focus_interval: Duration,
+    /// アプリケーション名からフォーカス時に切り替えるエンジン
+    pub rules: BTreeMap<String, String>,
+}
+
+impl Default for AppMemorySettings {
+    fn default() -> Self {
+        Self {
+            restore: false,
+            focus_interval: Duration::from_millis(250),
+            rules: BTreeMap::new(),
+        }
+    }
+}
+
+impl AppMemorySettings {
+    /// 戻すものも規則もなければフォーカスを追う必要はない
+    pub fn is_enabled(&self) -> bool {
+        self.restore || !self.rules.is_empty()
+    }
+}
+
+/// フォーカスとエンジンの変化から、アプリケーションごとのエンジンを覚える。
+#[derive(Debug, Clone, Default)]
+pub struct AppMemory {
+    settings: AppMemorySettings,
+    focused: Option<String>,
+    current: Option<String>,
+    remembered: HashMap<String, String>,
+}
+
+impl AppMemory {
+    pub fn new(settings: AppMemorySettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// `app`で最後に使われたエンジン
+    pub fn remembered(&self, app: &str) -> Option<&str> {
+        self.remembered.get(app).map(String::as_str)
+    }
+
+    /// 状態の変更を、フォーカスされているアプリケーションのエンジンとして覚える。
+    pub fn record(&mut self, state: &ImeState) {
+        self.current = Some(state.engine.clone());
+
+        if let Some(app) = &self.focused {
+            self.remembered.insert(app.clone(), state.engine.clone());
+        }
+    }
+
+    /// フォーカスが`app`に移った。切り替えるエンジンがあれば返す
+    ///
+    /// `restore`が有効なら覚えたエンジンを、覚えていなければ規則のエンジンを選ぶ。
+    /// 既にそのエンジンであれば切り替えない。
+    pub fn focus(&mut self, app: Option<String>) -> Option<String> {
+        if self.focused == app {
+            return None;
+        }
+        self.focused = app;
+
+        let app = self.focused.as_deref()?;
+        let remembered = self
+            .settings
+            .restore
+            .then(|| self.remembered(app))
+            .flatten();
+        let target = remembered.or(self.settings.rules.get(app).map(String::as_str))?;
+
+        (self.current.as_deref() != Some(target)).then(|| target.to_owned())
+    }
+
+    /// `events`が切断されるまで、フォーカスを確認しながら切り替える。
+    ///
+    /// 状態の変更は、届いた時点でフォーカスを確認し直してから覚える。フォーカスの取得に失敗し
+    /// 続けている間は最初の失敗だけを`report`に渡す。
+    pub fn run<F, S>(
+        mut self,
+        events: &Receiver<ImeEvent>,
+        mut focused_app: impl FnMut() -> Result<Option<String>, F>,
+        mut switch: impl FnMut(&str) -> Result<(), S>,
+        mut report: impl FnMut(&dyn std::error::Error),
+    ) where
+        F: std::error::Error,
+        S: std::error::Error,
+    {
+        let mut next_poll = Instant::now();
+        let mut failing = false;
+
+        loop {
+            let changed =
+                match events.recv_timeout(next_poll.saturating_duration_since(Instant::now())) {
+                    Ok(ImeEvent::Changed(state)) => Some(state),
+                    // 再接続後のエンジンは分からない
+                    Ok(ImeEvent::BackendLost) => {
+                        self.current = None;
+                        None
+                    }
+                    Ok(ImeEvent::BackendRecovered | ImeEvent::GroupChanged(_))
+                    | Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+            // 前回の確認の後にフォーカスが移っていれば、移った先のアプリケーションの変更として覚える
+            if changed.is_some() || Instant::now() >= next_poll {
+                next_poll = Instant::now() + self.settings.focus_interval;
+
+                match focused_app() {
+                    Ok(app) => {
+                        failing = false;
+
+                        if let Some(engine) = self.focus(app)
+                            && let Err(e) = switch(&engine)
+                        {
+                            report(&e);
+                        }
+                    }
+                    Err(e) => {
+                        if !failing {
+                            report(&e);
+                        }
+                        failing = true;
+                    }
+                }
+            }
+
+            if let Some(state) = changed {
+                self.record(&state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_memory(restore: bool, rules: &[(&str, &str)]) -> AppMemory {
+        AppMemory::new(AppMemorySettings {
+            restore,
+            rules: rules
+                .iter()
+                .map(|(app, engine)| (app.to_string(), engine.to_string()))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn app(name: &str) -> Option<String> {
+        Some(name.to_owned())
+    }
+
+    #[test]
+    fn restores_last_engine_of_each_app() {
+        let mut memory = app_memory(true, &[]);
+
+        memory.record(&ImeState::new("keyboard-us"));
+        assert_eq!(memory.focus(app("chat")), None);
+        memory.record(&ImeState::new("mozc"));
+
+        assert_eq!(memory.focus(app("terminal")), None);
+        memory.record(&ImeState::new("keyboard-us"));
+
+        assert_eq!(memory.focus(app("chat")), Some("mozc".to_owned()));
+        memory.record(&ImeState::new("mozc"));
+        // フォーカスが変わらなければ切り替えない
+        assert_eq!(memory.focus(app("chat")), None);
+
+        // フォーカスされたアプリケーションがない間は覚えない
+        assert_eq!(memory.focus(None), None);
+        memory.record(&ImeState::new("anthy"));
+        assert_eq!(memory.remembered("chat"), Some("mozc"));
+
+        assert_eq!(
+            memory.focus(app("terminal")),
+            Some("keyboard-us".to_owned())
+        );
+    }
+
+    #[test]
+    fn rules_apply_until_something_is_remembered() {
+        let mut memory = app_memory(true, &[("chat", "mozc"), ("terminal", "keyboard-us")]);
+
+        memory.record(&ImeState::new("keyboard-us"));
+        assert_eq!(memory.focus(app("chat")), Some("mozc".to_owned()));
+        memory.record(&ImeState::new("mozc"));
+        memory.record(&ImeState::new("anthy"));
+
+        assert_eq!(
+            memory.focus(app("terminal")),
+            Some("keyboard-us".to_owned())
+        );
+        memory.record(&ImeState::new("keyboard-us"));
+        assert_eq!(memory.focus(app("chat")), Some("anthy".to_owned()));
+
+        // 戻さない場合は規則のみ
+        let mut memory = app_memory(false, &[("chat", "mozc")]);
+        memory.record(&ImeState::new("keyboard-us"));
+        assert_eq!(memory.focus(app("chat")), Some("mozc".to_owned()));
+        memory.record(&ImeState::new("anthy"));
+        assert_eq!(memory.focus(app("browser")), None);
+        assert_eq!(memory.focus(app("chat")), Some("mozc".to_owned()));
+    }
+
+    #[test]
+    fn changes_are_recorded_under_the_app_focused_when_they_arrive() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        // 確認の間隔に頼らず、変更が届くたびにフォーカスを確認する
+        let memory = AppMemory::new(AppMemorySettings {
+            restore: true,
+            focus_interval: Duration::from_secs(3600),
+            ..Default::default()
+        });
+
+        let mut focused = ["chat", "terminal", "chat"].into_iter();
+        let mut switched = Vec::new();
+
+        // 確認の後にterminalへ移ってからmozcに切り替えた
+        for engine in ["keyboard-us", "mozc", "mozc"] {
+            sender
+                .send(ImeEvent::Changed(ImeState::new(engine)))
+                .unwrap();
+        }
+        drop(sender);
+
+        memory.run(
+            &receiver,
+            || Ok::<_, std::fmt::Error>(focused.next().map(str::to_owned)),
+            |engine| {
+                switched.push(engine.to_owned());
+                Ok::<_, std::fmt::Error>(())
+            },
+            |e| panic!("{e}"),
+        );
+
+        // mozcはterminalのエンジンとして覚え、chatには元のエンジンを戻す
+        assert_eq!(switched, ["keyboard-us"]);
+    }
+}
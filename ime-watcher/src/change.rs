@@ -0,0 +1,287 @@
+//! 同じ状態の繰り返しを取り除く、バックエンド共通の変更判定。
+//!
+//! fcitx5の`NewIcon`はアイコンの更新でも送られ、IBusの`GlobalEngineChanged`も
+//! 同じエンジンで繰り返されることがあるため、Linuxのバックエンドは配信前にここを通す。
+
+use serde::Deserialize;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Broadcaster, ImeEvent, ImeState};
+
+/// 状態を配信する条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmitPolicy {
+    /// 前回と異なる状態のみ
+    #[default]
+    OnChange,
+    /// 受け取った状態を全て
+    All,
+    /// 変更に加え、一定時間配信がなければ現在の状態を再送する
+    Heartbeat(Duration),
+}
+
+/// 設定ファイルでの`emit`の値。`Heartbeat`の間隔は別に指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmitMode {
+    Change,
+    All,
+    Heartbeat,
+}
+
+impl std::fmt::Display for EmitMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmitMode::Change => write!(f, "change"),
+            EmitMode::All => write!(f, "all"),
+            EmitMode::Heartbeat => write!(f, "heartbeat"),
+        }
+    }
+}
+
+impl std::str::FromStr for EmitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "change" => Ok(EmitMode::Change),
+            "all" => Ok(EmitMode::All),
+            "heartbeat" => Ok(EmitMode::Heartbeat),
+            other => Err(format!("unknown emit policy `{other}`")),
+        }
+    }
+}
+
+impl EmitPolicy {
+    pub fn mode(&self) -> EmitMode {
+        match self {
+            EmitPolicy::OnChange => EmitMode::Change,
+            EmitPolicy::All => EmitMode::All,
+            EmitPolicy::Heartbeat(_) => EmitMode::Heartbeat,
+        }
+    }
+}
+
+/// 最後に配信した状態を覚え、次のイベントを配信するか判定する。
+///
+/// 時刻は呼び出し側が渡すため、判定自体は時計に依存しない。
+#[derive(Debug, Clone, Default)]
+pub struct ChangeDetector {
+    policy: EmitPolicy,
+    last: Option<ImeState>,
+    last_emitted: Option<Instant>,
+}
+
+impl ChangeDetector {
+    pub fn new(policy: EmitPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// 配信するイベント。取り除く場合は`None`
+    ///
+    /// `BackendLost`の後は前回の状態を忘れ、再接続後の状態は必ず配信する。
+    pub fn filter(&mut self, event: ImeEvent, now: Instant) -> Option<ImeEvent> {
+        match event {
+            ImeEvent::Changed(state) => {
+                if self.policy != EmitPolicy::All && self.last.as_ref() == Some(&state) {
+                    return None;
+                }
+
+                self.last = Some(state.clone());
+                self.last_emitted = Some(now);
+
+                Some(ImeEvent::Changed(state))
+            }
+            ImeEvent::BackendLost => {
+                self.last = None;
+                self.last_emitted = None;
+
+                Some(ImeEvent::BackendLost)
+            }
+            ImeEvent::BackendRecovered => Some(ImeEvent::BackendRecovered),
+            // グループの内容の変更は同じ名前で届くため、繰り返しとはみなさない
+            ImeEvent::GroupChanged(group) => Some(ImeEvent::GroupChanged(group)),
+        }
+    }
+
+    /// 次のハートビートまでの時間。ハートビートを送らない場合は`None`
+    pub fn until_heartbeat(&self, now: Instant) -> Option<Duration> {
+        let EmitPolicy::Heartbeat(interval) = self.policy else {
+            return None;
+        };
+
+        // 最初の状態を受け取るまでは送るものがない
+        self.last.as_ref()?;
+        let elapsed = now.saturating_duration_since(self.last_emitted?);
+
+        Some(interval.saturating_sub(elapsed))
+    }
+
+    /// 送るべき時刻になっていればハートビートとして再送する状態
+    pub fn heartbeat(&mut self, now: Instant) -> Option<ImeState> {
+        if !self.until_heartbeat(now)?.is_zero() {
+            return None;
+        }
+
+        self.last_emitted = Some(now);
+        self.last.clone()
+    }
+}
+
+/// [`ChangeDetector`]を通して[`Broadcaster`]に配信する。
+///
+/// 複数のスレッドから配信するバックエンドのために判定をロックで守る。
+#[derive(Debug)]
+pub struct ChangeFilter {
+    broadcaster: Arc<Broadcaster>,
+    detector: Mutex<ChangeDetector>,
+}
+
+impl ChangeFilter {
+    pub fn new(broadcaster: Arc<Broadcaster>, policy: EmitPolicy) -> Self {
+        Self {
+            broadcaster,
+            detector: Mutex::new(ChangeDetector::new(policy)),
+        }
+    }
+
+    pub fn send(&self, event: ImeEvent) {
+        let event = self.detector.lock().unwrap().filter(event, Instant::now());
+
+        match event {
+            Some(ImeEvent::Changed(state)) => self.broadcaster.publish_state(state),
+            Some(event) => self.broadcaster.send(event),
+            None => {}
+        }
+    }
+
+    pub fn publish_state(&self, state: ImeState) {
+        self.send(ImeEvent::Changed(state));
+    }
+
+    /// 次に[`ChangeFilter::tick`]を呼ぶまでの最長の待ち時間
+    pub fn wait_timeout(&self, max: Duration) -> Duration {
+        let until_heartbeat = self
+            .detector
+            .lock()
+            .unwrap()
+            .until_heartbeat(Instant::now());
+
+        until_heartbeat.map_or(max, |wait| wait.min(max))
+    }
+
+    /// 時刻になっていればハートビートを送る。
+    pub fn tick(&self) {
+        let state = self.detector.lock().unwrap().heartbeat(Instant::now());
+
+        if let Some(state) = state {
+            self.broadcaster.publish_state(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputKind;
+
+    fn mozc() -> ImeState {
+        ImeState::new("mozc").with_kind(InputKind::Composing)
+    }
+
+    fn keyboard_us() -> ImeState {
+        ImeState::new("keyboard-us").with_kind(InputKind::Direct)
+    }
+
+    fn changed(state: ImeState) -> ImeEvent {
+        ImeEvent::Changed(state)
+    }
+
+    #[test]
+    fn on_change_drops_repeats_until_backend_is_lost() {
+        let mut detector = ChangeDetector::new(EmitPolicy::OnChange);
+        let now = Instant::now();
+
+        assert_eq!(detector.filter(changed(mozc()), now), Some(changed(mozc())));
+        assert_eq!(detector.filter(changed(mozc()), now), None);
+        assert_eq!(
+            detector.filter(changed(mozc().with_open(false)), now),
+            Some(changed(mozc().with_open(false)))
+        );
+        assert_eq!(
+            detector.filter(changed(keyboard_us()), now),
+            Some(changed(keyboard_us()))
+        );
+
+        // 再起動後は同じ状態でも配信する
+        assert_eq!(
+            detector.filter(ImeEvent::BackendLost, now),
+            Some(ImeEvent::BackendLost)
+        );
+        assert_eq!(
+            detector.filter(ImeEvent::BackendRecovered, now),
+            Some(ImeEvent::BackendRecovered)
+        );
+        assert_eq!(
+            detector.filter(changed(keyboard_us()), now),
+            Some(changed(keyboard_us()))
+        );
+
+        assert_eq!(detector.until_heartbeat(now), None);
+    }
+
+    #[test]
+    fn all_keeps_repeats() {
+        let mut detector = ChangeDetector::new(EmitPolicy::All);
+        let now = Instant::now();
+
+        assert_eq!(detector.filter(changed(mozc()), now), Some(changed(mozc())));
+        assert_eq!(detector.filter(changed(mozc()), now), Some(changed(mozc())));
+    }
+
+    #[test]
+    fn heartbeat_repeats_after_silence() {
+        let interval = Duration::from_secs(30);
+        let mut detector = ChangeDetector::new(EmitPolicy::Heartbeat(interval));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // 最初の状態を受け取るまでは送らない
+        assert_eq!(detector.until_heartbeat(at(100)), None);
+        assert_eq!(detector.heartbeat(at(100)), None);
+
+        assert_eq!(
+            detector.filter(changed(mozc()), at(0)),
+            Some(changed(mozc()))
+        );
+        assert_eq!(detector.filter(changed(mozc()), at(10)), None);
+
+        // 取り除かれた繰り返しでは間隔は延びない
+        assert_eq!(
+            detector.until_heartbeat(at(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(detector.heartbeat(at(29)), None);
+        assert_eq!(detector.heartbeat(at(30)), Some(mozc()));
+
+        // 変更の配信でも間隔は始まり直す
+        assert_eq!(
+            detector.until_heartbeat(at(31)),
+            Some(Duration::from_secs(29))
+        );
+        assert_eq!(
+            detector.filter(changed(keyboard_us()), at(50)),
+            Some(changed(keyboard_us()))
+        );
+        assert_eq!(detector.heartbeat(at(60)), None);
+        assert_eq!(detector.heartbeat(at(90)), Some(keyboard_us()));
+
+        detector.filter(ImeEvent::BackendLost, at(100));
+        assert_eq!(detector.heartbeat(at(200)), None);
+    }
+}
@@ -0,0 +1,334 @@
+//! fcitx5/IBusのエンジン名から直接入力か変換入力か、及び言語を判定する。
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::config_dir;
+use crate::{ImeState, InputKind};
+
+/// 判定結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classification {
+    pub kind: InputKind,
+    pub language: Option<String>,
+}
+
+/// 上書き用TOMLの各エントリ。
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    kind: InputKind,
+    language: Option<String>,
+}
+
+/// 上書き用TOMLのルート。
+///
+/// ```toml
+/// [engines]
+/// "mozc-jp" = { kind = "composing", language = "ja" }
+///
+/// [prefixes]
+/// "table:" = { kind = "composing", language = "zh" }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    #[serde(default)]
+    engines: HashMap<String, Entry>,
+    #[serde(default)]
+    prefixes: HashMap<String, Entry>,
+}
+
+/// 組み込みのエンジン名(完全一致)。
+const BUILTIN_ENGINES: &[(&str, InputKind, &str)] = &[
+    ("mozc", InputKind::Composing, "ja"),
+    ("mozc-jp", InputKind::Composing, "ja"),
+    ("anthy", InputKind::Composing, "ja"),
+    ("kkc", InputKind::Composing, "ja"),
+    ("skk", InputKind::Composing, "ja"),
+    ("rime", InputKind::Composing, "zh"),
+    ("pinyin", InputKind::Composing, "zh"),
+    ("shuangpin", InputKind::Composing, "zh"),
+    ("libpinyin", InputKind::Composing, "zh"),
+    ("libbopomofo", InputKind::Composing, "zh-TW"),
+    ("chewing", InputKind::Composing, "zh-TW"),
+    ("hangul", InputKind::Composing, "ko"),
+    ("unikey", InputKind::Composing, "vi"),
+    ("bamboo", InputKind::Composing, "vi"),
+];
+
+/// xkbのレイアウト名 -> 言語
+const LAYOUT_LANGUAGES: &[(&str, &str)] = &[
+    ("us", "en"),
+    ("gb", "en"),
+    ("jp", "ja"),
+    ("kr", "ko"),
+    ("cn", "zh"),
+    ("tw", "zh-TW"),
+    ("de", "de"),
+    ("fr", "fr"),
+    ("es", "es"),
+    ("it", "it"),
+    ("ru", "ru"),
+    ("br", "pt"),
+    ("pt", "pt"),
+    ("vn", "vi"),
+];
+
+/// ISO 639-2 -> ISO 639-1
+const ISO639_2_LANGUAGES: &[(&str, &str)] = &[
+    ("eng", "en"),
+    ("jpn", "ja"),
+    ("kor", "ko"),
+    ("zho", "zh"),
+    ("chi", "zh"),
+    ("deu", "de"),
+    ("ger", "de"),
+    ("fra", "fr"),
+    ("fre", "fr"),
+    ("spa", "es"),
+    ("ita", "it"),
+    ("rus", "ru"),
+    ("por", "pt"),
+    ("vie", "vi"),
+];
+
+fn lookup(table: &[(&str, &str)], key: &str) -> Option<String> {
+    table
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.to_string())
+}
+
+/// 組み込みの判定。一致しないエンジンの種類と言語は分からないものとする。
+fn classify_builtin(engine: &str) -> Classification {
+    // fcitx5: keyboard-<layout>[-<variant>]
+    if let Some(layout) = engine.strip_prefix("keyboard-") {
+        let layout = layout.split('-').next().unwrap_or(layout);
+
+        return Classification {
+            kind: InputKind::Direct,
+            language: lookup(LAYOUT_LANGUAGES, layout),
+        };
+    }
+
+    // IBus: xkb:<layout>:<variant>:<language>
+    if let Some(xkb) = engine.strip_prefix("xkb:") {
+        let mut fields = xkb.split(':');
+        let layout = fields.next().unwrap_or_default();
+        let language = fields.nth(1).unwrap_or_default();
+
+        let language = match language {
+            "" => lookup(LAYOUT_LANGUAGES, layout),
+            language => lookup(ISO639_2_LANGUAGES, language).or(Some(language.to_string())),
+        };
+
+        return Classification {
+            kind: InputKind::Direct,
+            language,
+        };
+    }
+
+    // IBus: m17n:<language>:<name>
+    if let Some(m17n) = engine.strip_prefix("m17n:") {
+        return Classification {
+            kind: InputKind::Composing,
+            language: m17n.split(':').next().map(str::to_string),
+        };
+    }
+
+    match BUILTIN_ENGINES.iter().find(|(name, _, _)| *name == engine) {
+        Some((_, kind, language)) => Classification {
+            kind: *kind,
+            language: Some(language.to_string()),
+        },
+        None => Classification {
+            kind: InputKind::Unknown,
+            language: None,
+        },
+    }
+}
+
+#[derive(Debug)]
+pub enum ClassifyError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl std::fmt::Display for ClassifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClassifyError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            ClassifyError::Parse(e) => write!(f, "invalid engine overrides: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClassifyError {}
+
+/// 組み込みのテーブルにユーザーの上書きを重ねた判定器。
+#[derive(Debug, Default)]
+pub struct EngineClassifier {
+    overrides: Overrides,
+}
+
+impl EngineClassifier {
+    /// 組み込みのテーブルのみを用いる。
+    pub fn builtin() -> Self {
+        Self::default()
+    }
+
+    /// `$XDG_CONFIG_HOME/ime-watcher/engines.toml`
+    pub fn user_overrides_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("ime-watcher").join("engines.toml"))
+    }
+
+    /// ユーザーの上書きファイルが存在すれば読み込む。
+    pub fn from_user_config() -> Result<Self, ClassifyError> {
+        match Self::user_overrides_path() {
+            Some(path) if path.exists() => Self::builtin().with_overrides_file(path),
+            _ => Ok(Self::builtin()),
+        }
+    }
+
+    pub fn with_overrides_file(self, path: impl AsRef<Path>) -> Result<Self, ClassifyError> {
+        let path = path.as_ref();
+        let toml =
+            std::fs::read_to_string(path).map_err(|e| ClassifyError::Io(path.to_path_buf(), e))?;
+
+        self.with_overrides_toml(&toml)
+    }
+
+    /// 後から追加したものが優先される。
+    pub fn with_overrides_toml(mut self, toml: &str) -> Result<Self, ClassifyError> {
+        let overrides: Overrides = toml::from_str(toml).map_err(ClassifyError::Parse)?;
+
+        self.overrides.engines.extend(overrides.engines);
+        self.overrides.prefixes.extend(overrides.prefixes);
+
+        Ok(self)
+    }
+
+    pub fn classify(&self, engine: &str) -> Classification {
+        // 完全一致 -> 最長の前方一致 -> 組み込み
+        let entry = self.overrides.engines.get(engine).or_else(|| {
+            self.overrides
+                .prefixes
+                .iter()
+                .filter(|(prefix, _)| engine.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, entry)| entry)
+        });
+
+        match entry {
+            Some(entry) => Classification {
+                kind: entry.kind,
+                language: entry.language.clone(),
+            },
+            None => classify_builtin(engine),
+        }
+    }
+
+    /// エンジン名から状態を作る。
+    pub fn state(&self, engine: impl Into<String>) -> ImeState {
+        let engine = engine.into();
+        let Classification { kind, language } = self.classify(&engine);
+
+        ImeState {
+            kind,
+            language,
+            ..ImeState::new(engine)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_classified(engine: &str, kind: InputKind, language: Option<&str>) {
+        let classification = EngineClassifier::builtin().classify(engine);
+
+        assert_eq!(classification.kind, kind, "{engine}");
+        assert_eq!(classification.language.as_deref(), language, "{engine}");
+    }
+
+    #[test]
+    fn fcitx5_keyboard_layouts_are_direct() {
+        assert_classified("keyboard-us", InputKind::Direct, Some("en"));
+        assert_classified("keyboard-jp", InputKind::Direct, Some("ja"));
+        assert_classified("keyboard-us-dvorak", InputKind::Direct, Some("en"));
+        assert_classified("keyboard-xx", InputKind::Direct, None);
+    }
+
+    #[test]
+    fn ibus_xkb_engines_are_direct() {
+        assert_classified("xkb:us::eng", InputKind::Direct, Some("en"));
+        assert_classified("xkb:jp::jpn", InputKind::Direct, Some("ja"));
+        assert_classified("xkb:de:nodeadkeys:ger", InputKind::Direct, Some("de"));
+        assert_classified("xkb:xx::abc", InputKind::Direct, Some("abc"));
+    }
+
+    #[test]
+    fn common_imes_are_composing() {
+        assert_classified("mozc", InputKind::Composing, Some("ja"));
+        assert_classified("mozc-jp", InputKind::Composing, Some("ja"));
+        assert_classified("anthy", InputKind::Composing, Some("ja"));
+        assert_classified("rime", InputKind::Composing, Some("zh"));
+        assert_classified("hangul", InputKind::Composing, Some("ko"));
+        assert_classified("m17n:hi:itrans", InputKind::Composing, Some("hi"));
+    }
+
+    #[test]
+    fn unmatched_engines_are_unknown() {
+        assert_classified("unknown-engine", InputKind::Unknown, None);
+        assert!(
+            !EngineClassifier::builtin()
+                .state("unknown-engine")
+                .is_active()
+        );
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let classifier = EngineClassifier::builtin()
+            .with_overrides_toml(
+                r#"
+                [engines]
+                "mozc" = { kind = "direct" }
+
+                [prefixes]
+                "table:" = { kind = "composing", language = "zh" }
+                "table:cangjie" = { kind = "composing", language = "zh-HK" }
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            classifier.classify("mozc"),
+            Classification {
+                kind: InputKind::Direct,
+                language: None
+            }
+        );
+        assert_eq!(
+            classifier.classify("table:wubi").language.as_deref(),
+            Some("zh")
+        );
+        assert_eq!(
+            classifier.classify("table:cangjie5").language.as_deref(),
+            Some("zh-HK")
+        );
+        assert_eq!(classifier.classify("anthy").language.as_deref(), Some("ja"));
+    }
+
+    #[test]
+    fn invalid_overrides_are_rejected() {
+        let res = EngineClassifier::builtin()
+            .with_overrides_toml(r#"engines = { mozc = { kind = "maybe" } }"#);
+
+        assert!(matches!(res, Err(ClassifyError::Parse(_))));
+    }
+}
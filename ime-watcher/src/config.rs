@@ -0,0 +1,605 @@
+//! 設定ファイル、環境変数、コマンドラインの順に重ねた設定。
+//!
+//! `$XDG_CONFIG_HOME/ime-watcher/config.toml`に次のように書く。全て省略できる。
+//!
+//! ```toml
+//! backend = "auto"            # "auto", "fcitx5", "ibus"
+//! format = "text"             # "text", "json"
+//! dbus_timeout_ms = 500       # D-Busの問い合わせのタイムアウト
+//! process_interval_ms = 1000  # 停止要求や再起動を確認する間隔
+//! notify_delay_ms = 40        # 通知が途切れてから状態を取得するまでの時間。既定はmacOSで40、Windowsで50
+//! sni_id = "Fcitx"            # fcitx5のStatusNotifierItemの`Id`
+//! emit = "change"             # "change", "all", "heartbeat"
+//! heartbeat_secs = 30         # `emit = "heartbeat"`で再送する間隔
+//! debounce_quiet_ms = 30      # 続けて届くシグナルが途切れたとみなすまでの時間
+//! debounce_max_wait_ms = 200  # シグナルが途切れなくても取得するまでの時間。0で無効
+//! restore_per_app = false     # フォーカスが戻ったアプリケーションを最後のエンジンに戻す(Linux)
+//! focus_interval_ms = 250     # フォーカスを確認する間隔
+//!
+//! # アプリケーション(fcitx5のプログラム名かX11の`WM_CLASS`のクラス)ごとにフォーカス時のエンジンを決める。
+//! # `restore_per_app`が有効なら、覚えたエンジンがない場合のみ用いる
+//! [apps]
+//! "org.telegram.desktop" = "mozc"
+//! kitty = "keyboard-us"
+//! ```
+//!
+//! 環境変数は`IME_WATCHER_BACKEND`のように各キーを大文字にして`IME_WATCHER_`を付ける。
+//! 設定ファイルの場所は`IME_WATCHER_CONFIG`で変えられる。
+
+use serde::Deserialize;
+
+use crate::app_memory::AppMemorySettings;
+use crate::change::{EmitMode, EmitPolicy};
+use crate::debounce::DebounceSettings;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const ENV_PREFIX: &str = "IME_WATCHER_";
+
+/// 時間の設定として受け付ける範囲(ms)
+const MAX_DURATION_MS: u64 = 60_000;
+
+/// ハートビートの間隔として受け付ける範囲(秒)
+const MAX_HEARTBEAT_SECS: u64 = 86_400;
+
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// 使用するバックエンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendChoice {
+    /// 起動しているものを判定する
+    #[default]
+    Auto,
+    Fcitx5,
+    Ibus,
+}
+
+impl std::fmt::Display for BackendChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendChoice::Auto => write!(f, "auto"),
+            BackendChoice::Fcitx5 => write!(f, "fcitx5"),
+            BackendChoice::Ibus => write!(f, "ibus"),
+        }
+    }
+}
+
+impl std::str::FromStr for BackendChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BackendChoice::Auto),
+            "fcitx5" => Ok(BackendChoice::Fcitx5),
+            "ibus" => Ok(BackendChoice::Ibus),
+            other => Err(format!("unknown backend `{other}`")),
+        }
+    }
+}
+
+/// 出力の形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// 1行に1オブジェクトのJSON Lines
+    #[serde(alias = "jsonl")]
+    Json,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" | "jsonl" => Ok(OutputFormat::Json),
+            other => Err(format!("unknown format `{other}`")),
+        }
+    }
+}
+
+/// 1つの層。指定されなかった項目は下の層の値を用いる
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub backend: Option<BackendChoice>,
+    pub format: Option<OutputFormat>,
+    pub dbus_timeout_ms: Option<u64>,
+    pub process_interval_ms: Option<u64>,
+    pub notify_delay_ms: Option<u64>,
+    pub sni_id: Option<String>,
+    pub emit: Option<EmitMode>,
+    pub heartbeat_secs: Option<u64>,
+    pub debounce_quiet_ms: Option<u64>,
+    pub debounce_max_wait_ms: Option<u64>,
+    pub restore_per_app: Option<bool>,
+    pub focus_interval_ms: Option<u64>,
+    /// 設定ファイルでのみ指定できる。層を重ねるとアプリケーションごとに上書きする
+    pub apps: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// 環境変数の値が読めない
+    Env(String, String),
+    /// 範囲外などの不正な値
+    Invalid(&'static str, String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {e}", path.display()),
+            ConfigError::Env(key, reason) => write!(f, "invalid {key}: {reason}"),
+            ConfigError::Invalid(key, reason) => write!(f, "invalid {key}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigLayer {
+    pub fn from_toml(toml: &str, path: &Path) -> Result<Self, ConfigError> {
+        toml::from_str(toml).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let toml =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+
+        Self::from_toml(&toml, path)
+    }
+
+    /// `IME_WATCHER_`で始まる変数から作る。空の値は指定されなかったものとする
+    pub fn from_vars(
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut layer = Self::default();
+
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if value.is_empty() {
+                continue;
+            }
+
+            let invalid = |reason: String| ConfigError::Env(key.clone(), reason);
+            let number = || value.parse::<u64>().map_err(|e| invalid(e.to_string()));
+
+            match name {
+                "BACKEND" => layer.backend = Some(value.parse().map_err(invalid)?),
+                "FORMAT" => layer.format = Some(value.parse().map_err(invalid)?),
+                "DBUS_TIMEOUT_MS" => layer.dbus_timeout_ms = Some(number()?),
+                "PROCESS_INTERVAL_MS" => layer.process_interval_ms = Some(number()?),
+                "NOTIFY_DELAY_MS" => layer.notify_delay_ms = Some(number()?),
+                "SNI_ID" => layer.sni_id = Some(value.clone()),
+                "EMIT" => layer.emit = Some(value.parse().map_err(invalid)?),
+                "HEARTBEAT_SECS" => layer.heartbeat_secs = Some(number()?),
+                "DEBOUNCE_QUIET_MS" => layer.debounce_quiet_ms = Some(number()?),
+                "DEBOUNCE_MAX_WAIT_MS" => layer.debounce_max_wait_ms = Some(number()?),
+                "RESTORE_PER_APP" => {
+                    layer.restore_per_app = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid(format!("`{value}` is not a bool")))?,
+                    );
+                }
+                "FOCUS_INTERVAL_MS" => layer.focus_interval_ms = Some(number()?),
+                // `IME_WATCHER_CONFIG`などの設定項目でない変数
+                _ => {}
+            }
+        }
+
+        Ok(layer)
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// `upper`で指定された項目を上書きする。
+    pub fn merge(self, upper: ConfigLayer) -> Self {
+        Self {
+            backend: upper.backend.or(self.backend),
+            format: upper.format.or(self.format),
+            dbus_timeout_ms: upper.dbus_timeout_ms.or(self.dbus_timeout_ms),
+            process_interval_ms: upper.process_interval_ms.or(self.process_interval_ms),
+            notify_delay_ms: upper.notify_delay_ms.or(self.notify_delay_ms),
+            sni_id: upper.sni_id.or(self.sni_id),
+            emit: upper.emit.or(self.emit),
+            heartbeat_secs: upper.heartbeat_secs.or(self.heartbeat_secs),
+            debounce_quiet_ms: upper.debounce_quiet_ms.or(self.debounce_quiet_ms),
+            debounce_max_wait_ms: upper.debounce_max_wait_ms.or(self.debounce_max_wait_ms),
+            restore_per_app: upper.restore_per_app.or(self.restore_per_app),
+            focus_interval_ms: upper.focus_interval_ms.or(self.focus_interval_ms),
+            apps: match (self.apps, upper.apps) {
+                (Some(mut apps), Some(upper)) => {
+                    apps.extend(upper);
+                    Some(apps)
+                }
+                (apps, upper) => upper.or(apps),
+            },
+        }
+    }
+}
+
+/// 全ての層を重ねた設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub backend: BackendChoice,
+    pub format: OutputFormat,
+    pub dbus_timeout: Duration,
+    pub process_interval: Duration,
+    /// 指定がなければ各プラットフォームの既定値を用いる
+    pub notify_delay: Option<Duration>,
+    pub sni_id: String,
+    pub emit: EmitPolicy,
+    pub debounce: DebounceSettings,
+    pub app_memory: AppMemorySettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backend: BackendChoice::Auto,
+            format: OutputFormat::Text,
+            dbus_timeout: Duration::from_millis(500),
+            process_interval: Duration::from_millis(1000),
+            notify_delay: None,
+            sni_id: "Fcitx".to_owned(),
+            emit: EmitPolicy::OnChange,
+            debounce: DebounceSettings::default(),
+            app_memory: AppMemorySettings::default(),
+        }
+    }
+}
+
+fn millis(key: &'static str, value: u64, min: u64) -> Result<Duration, ConfigError> {
+    if !(min..=MAX_DURATION_MS).contains(&value) {
+        return Err(ConfigError::Invalid(
+            key,
+            format!("{value} is out of range {min}..={MAX_DURATION_MS}"),
+        ));
+    }
+
+    Ok(Duration::from_millis(value))
+}
+
+/// `$XDG_CONFIG_HOME`。空または未設定の場合は`$HOME/.config`
+pub fn config_dir() -> Option<PathBuf> {
+    let non_empty_var = |key| std::env::var_os(key).filter(|value| !value.is_empty());
+
+    non_empty_var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(non_empty_var("HOME")?).join(".config")))
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/ime-watcher/config.toml`
+    pub fn user_config_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("ime-watcher").join("config.toml"))
+    }
+
+    /// 既定値に`layer`を重ね、値を検証する。
+    pub fn from_layer(layer: ConfigLayer) -> Result<Self, ConfigError> {
+        let default = Self::default();
+
+        let sni_id = layer.sni_id.unwrap_or(default.sni_id);
+        if sni_id.trim().is_empty() {
+            return Err(ConfigError::Invalid(
+                "sni_id",
+                "must not be empty".to_owned(),
+            ));
+        }
+
+        let heartbeat = match layer.heartbeat_secs {
+            Some(secs) if !(1..=MAX_HEARTBEAT_SECS).contains(&secs) => {
+                return Err(ConfigError::Invalid(
+                    "heartbeat_secs",
+                    format!("{secs} is out of range 1..={MAX_HEARTBEAT_SECS}"),
+                ));
+            }
+            Some(secs) => Duration::from_secs(secs),
+            None => DEFAULT_HEARTBEAT,
+        };
+
+        let emit = match layer.emit {
+            Some(EmitMode::Change) => EmitPolicy::OnChange,
+            Some(EmitMode::All) => EmitPolicy::All,
+            Some(EmitMode::Heartbeat) => EmitPolicy::Heartbeat(heartbeat),
+            None => default.emit,
+        };
+
+        let debounce = DebounceSettings {
+            quiet: match layer.debounce_quiet_ms {
+                Some(value) => millis("debounce_quiet_ms", value, 0)?,
+                None => default.debounce.quiet,
+            },
+            max_wait: match layer.debounce_max_wait_ms {
+                Some(0) => None,
+                Some(value) => Some(millis("debounce_max_wait_ms", value, 1)?),
+                None => default.debounce.max_wait,
+            },
+            ..default.debounce
+        };
+
+        let app_memory = AppMemorySettings {
+            restore: layer.restore_per_app.unwrap_or(default.app_memory.restore),
+            focus_interval: match layer.focus_interval_ms {
+                Some(value) => millis("focus_interval_ms", value, 1)?,
+                None => default.app_memory.focus_interval,
+            },
+            rules: layer.apps.unwrap_or_default(),
+        };
+
+        Ok(Self {
+            backend: layer.backend.unwrap_or(default.backend),
+            format: layer.format.unwrap_or(default.format),
+            dbus_timeout: match layer.dbus_timeout_ms {
+                Some(value) => millis("dbus_timeout_ms", value, 1)?,
+                None => default.dbus_timeout,
+            },
+            process_interval: match layer.process_interval_ms {
+                Some(value) => millis("process_interval_ms", value, 1)?,
+                None => default.process_interval,
+            },
+            notify_delay: layer
+                .notify_delay_ms
+                .map(|value| millis("notify_delay_ms", value, 0))
+                .transpose()?,
+            sni_id,
+            emit,
+            debounce,
+            app_memory,
+        })
+    }
+
+    /// 設定ファイル、環境変数、`cli`の順に重ねる。
+    ///
+    /// 設定ファイルは`path`、`IME_WATCHER_CONFIG`、ユーザーの設定ファイルの順に探す。
+    /// 明示されたファイルは存在しなければエラーとなる
+    pub fn load(path: Option<&Path>, cli: ConfigLayer) -> Result<Self, ConfigError> {
+        let env_path = std::env::var_os("IME_WATCHER_CONFIG")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
+        let file = match path.or(env_path.as_deref()) {
+            Some(path) => ConfigLayer::from_file(path)?,
+            None => match Self::user_config_path() {
+                Some(path) if path.exists() => ConfigLayer::from_file(path)?,
+                _ => ConfigLayer::default(),
+            },
+        };
+
+        Self::from_layer(file.merge(ConfigLayer::from_env()?).merge(cli))
+    }
+
+    /// 設定ファイルと同じ形式で書き出す。
+    pub fn to_toml(&self) -> String {
+        let mut toml = format!(
+            "backend = \"{}\"\nformat = \"{}\"\ndbus_timeout_ms = {}\nprocess_interval_ms = {}\n",
+            self.backend,
+            self.format,
+            self.dbus_timeout.as_millis(),
+            self.process_interval.as_millis(),
+        );
+
+        match self.notify_delay {
+            Some(delay) => toml.push_str(&format!("notify_delay_ms = {}\n", delay.as_millis())),
+            None => toml.push_str("# notify_delay_ms = (platform default)\n"),
+        }
+
+        toml.push_str(&format!("sni_id = {}\n", toml_string(&self.sni_id)));
+
+        toml.push_str(&format!("emit = \"{}\"\n", self.emit.mode()));
+        if let EmitPolicy::Heartbeat(interval) = self.emit {
+            toml.push_str(&format!("heartbeat_secs = {}\n", interval.as_secs()));
+        }
+
+        toml.push_str(&format!(
+            "debounce_quiet_ms = {}\ndebounce_max_wait_ms = {}\n",
+            self.debounce.quiet.as_millis(),
+            self.debounce.max_wait.unwrap_or_default().as_millis(),
+        ));
+
+        toml.push_str(&format!(
+            "restore_per_app = {}\nfocus_interval_ms = {}\n",
+            self.app_memory.restore,
+            self.app_memory.focus_interval.as_millis(),
+        ));
+
+        // テーブルは最後に置く
+        if !self.app_memory.rules.is_empty() {
+            toml.push_str("\n[apps]\n");
+            for (app, engine) in &self.app_memory.rules {
+                toml.push_str(&format!("{} = {}\n", toml_string(app), toml_string(engine)));
+            }
+        }
+
+        toml
+    }
+}
+
+/// TOMLの基本文字列
+pub(crate) fn toml_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layers_override_in_order() {
+        let file = ConfigLayer::from_toml(
+            "backend = \"ibus\"\nformat = \"jsonl\"\ndbus_timeout_ms = 800\nsni_id = \"Fcitx5\"",
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        let env = ConfigLayer::from_vars(vars(&[
+            ("IME_WATCHER_DBUS_TIMEOUT_MS", "1500"),
+            ("IME_WATCHER_HEARTBEAT_SECS", "5"),
+            ("IME_WATCHER_BACKEND", ""),
+            ("IME_WATCHER_CONFIG", "/somewhere/config.toml"),
+            ("HOME", "/home/user"),
+        ]))
+        .unwrap();
+        let cli = ConfigLayer {
+            backend: Some(BackendChoice::Fcitx5),
+            emit: Some(EmitMode::Heartbeat),
+            ..Default::default()
+        };
+
+        let config = Config::from_layer(file.merge(env).merge(cli)).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                backend: BackendChoice::Fcitx5,
+                format: OutputFormat::Json,
+                dbus_timeout: Duration::from_millis(1500),
+                sni_id: "Fcitx5".to_owned(),
+                emit: EmitPolicy::Heartbeat(Duration::from_secs(5)),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(matches!(
+            ConfigLayer::from_toml("timeout = 10", Path::new("config.toml")),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            ConfigLayer::from_vars(vars(&[("IME_WATCHER_FORMAT", "yaml")])),
+            Err(ConfigError::Env(..))
+        ));
+        assert!(matches!(
+            ConfigLayer::from_vars(vars(&[("IME_WATCHER_DBUS_TIMEOUT_MS", "-1")])),
+            Err(ConfigError::Env(..))
+        ));
+
+        let invalid = |layer| match Config::from_layer(layer) {
+            Err(ConfigError::Invalid(key, _)) => key,
+            res => panic!("unexpected {res:?}"),
+        };
+
+        assert_eq!(
+            invalid(ConfigLayer {
+                dbus_timeout_ms: Some(0),
+                ..Default::default()
+            }),
+            "dbus_timeout_ms"
+        );
+        assert_eq!(
+            invalid(ConfigLayer {
+                process_interval_ms: Some(600_000),
+                ..Default::default()
+            }),
+            "process_interval_ms"
+        );
+        assert_eq!(
+            invalid(ConfigLayer {
+                sni_id: Some(" ".to_owned()),
+                ..Default::default()
+            }),
+            "sni_id"
+        );
+        assert_eq!(
+            invalid(ConfigLayer {
+                heartbeat_secs: Some(0),
+                ..Default::default()
+            }),
+            "heartbeat_secs"
+        );
+    }
+
+    #[test]
+    fn printed_config_reads_back() {
+        let config = Config {
+            notify_delay: Some(Duration::from_millis(0)),
+            sni_id: "Fc\"itx".to_owned(),
+            emit: EmitPolicy::Heartbeat(Duration::from_secs(60)),
+            debounce: DebounceSettings {
+                max_wait: None,
+                ..DebounceSettings::default()
+            },
+            app_memory: AppMemorySettings {
+                restore: true,
+                rules: BTreeMap::from([
+                    ("org.telegram.desktop".to_owned(), "mozc".to_owned()),
+                    ("odd \"app\"".to_owned(), "keyboard-us".to_owned()),
+                ]),
+                ..AppMemorySettings::default()
+            },
+            ..Config::default()
+        };
+
+        let layer = ConfigLayer::from_toml(&config.to_toml(), Path::new("printed")).unwrap();
+        assert_eq!(Config::from_layer(layer).unwrap(), config);
+
+        let layer = ConfigLayer::from_toml(&Config::default().to_toml(), Path::new("printed"));
+        assert_eq!(
+            Config::from_layer(layer.unwrap()).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn toml_strings_read_back() {
+        for value in [
+            "mozc",
+            "a\"b\\c",
+            "line\nbreak\r\ttab",
+            "\u{0}\u{1b}\u{7f}",
+            "日本語",
+        ] {
+            let toml = format!("value = {}", toml_string(value));
+            let table: toml::Table = toml::from_str(&toml).unwrap();
+
+            assert_eq!(table["value"].as_str(), Some(value), "{toml}");
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! 連続する通知をまとめるデバウンサー。
+//!
+//! 入力メソッドの切り替えではアイコンやプロパティの更新が短時間に続けて届くため、
+//! 通知ごとに状態を取得する代わりにまとめて取得する。判定は時刻を引数に取るため、
+//! 時計に依存せずに確かめられる。
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// 一連の通知のどこで実行するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Edge {
+    /// 最初の通知で即座に実行し、続く通知は無視する
+    Leading,
+    /// 通知が途切れてから実行する
+    Trailing,
+    /// 最初の通知で実行し、続く通知があれば途切れてからもう一度実行する
+    #[default]
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceSettings {
+    pub edge: Edge,
+    /// 通知が途切れたとみなすまでの時間
+    pub quiet: Duration,
+    /// 通知が途切れなくても実行するまでの時間。`None`なら途切れるまで待つ
+    pub max_wait: Option<Duration>,
+}
+
+impl Default for DebounceSettings {
+    fn default() -> Self {
+        Self {
+            edge: Edge::Both,
+            quiet: Duration::from_millis(30),
+            max_wait: Some(Duration::from_millis(200)),
+        }
+    }
+}
+
+/// 現在の一連の通知
+#[derive(Debug, Clone, Copy)]
+struct Burst {
+    /// 最後に実行した時刻、まだ実行していなければ最初の通知の時刻
+    since: Instant,
+    last_notified: Instant,
+    /// 実行されていない通知があるか
+    pending: bool,
+}
+
+/// 通知と時刻から実行するタイミングを決める。
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    settings: DebounceSettings,
+    burst: Option<Burst>,
+}
+
+impl Debouncer {
+    pub fn new(settings: DebounceSettings) -> Self {
+        Self {
+            settings,
+            burst: None,
+        }
+    }
+
+    /// 通知を記録する。すぐに実行する場合は`true`
+    pub fn notify(&mut self, now: Instant) -> bool {
+        match &mut self.burst {
+            Some(burst) => {
+                burst.last_notified = now;
+                burst.pending = self.settings.edge != Edge::Leading;
+
+                false
+            }
+            None => {
+                let leading = self.settings.edge != Edge::Trailing;
+
+                self.burst = Some(Burst {
+                    since: now,
+                    last_notified: now,
+                    pending: !leading,
+                });
+
+                leading
+            }
+        }
+    }
+
+    /// 次に[`Debouncer::poll`]を呼ぶ時刻。通知を待っていない場合は`None`
+    pub fn deadline(&self) -> Option<Instant> {
+        let burst = self.burst?;
+        let quiet_end = burst.last_notified + self.settings.quiet;
+
+        match self.settings.max_wait {
+            Some(max_wait) if burst.pending => Some(quiet_end.min(burst.since + max_wait)),
+            _ => Some(quiet_end),
+        }
+    }
+
+    /// 期限を過ぎていれば一連の通知を締める。実行する場合は`true`
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let Some(deadline) = self.deadline() else {
+            return false;
+        };
+        if now < deadline {
+            return false;
+        }
+
+        let Some(burst) = &mut self.burst else {
+            return false;
+        };
+        let pending = burst.pending;
+
+        if now >= burst.last_notified + self.settings.quiet {
+            self.burst = None;
+        } else {
+            // 通知が続いているため、実行して次の最長待ち時間を数え始める
+            burst.since = now;
+            burst.pending = false;
+        }
+
+        pending
+    }
+
+    /// `receiver`が切断されるまで通知をまとめて`action`を実行する。
+    pub fn run<T>(mut self, receiver: &Receiver<T>, mut action: impl FnMut()) {
+        loop {
+            // 通知が絶え間なく届いても最長待ち時間を守る
+            if self.poll(Instant::now()) {
+                action();
+            }
+
+            let received = match self.deadline() {
+                Some(deadline) => {
+                    receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            let run = match received {
+                Ok(_) => self.notify(Instant::now()),
+                Err(RecvTimeoutError::Timeout) => self.poll(Instant::now()),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if run {
+                action();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 開始からの経過時間で時刻を指定する時計
+    struct FakeClock {
+        start: Instant,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+            }
+        }
+
+        fn at(&self, millis: u64) -> Instant {
+            self.start + Duration::from_millis(millis)
+        }
+    }
+
+    fn debouncer(edge: Edge, max_wait: Option<u64>) -> Debouncer {
+        Debouncer::new(DebounceSettings {
+            edge,
+            quiet: Duration::from_millis(30),
+            max_wait: max_wait.map(Duration::from_millis),
+        })
+    }
+
+    /// 各時刻に通知し、期限ごとに`poll`した結果の実行時刻
+    fn runs(mut debouncer: Debouncer, notifications: &[u64]) -> Vec<u64> {
+        let clock = FakeClock::new();
+        let mut runs = Vec::new();
+        let mut notifications = notifications.iter().peekable();
+
+        loop {
+            let deadline = debouncer
+                .deadline()
+                .map(|deadline| (deadline - clock.start).as_millis() as u64);
+
+            match (notifications.peek(), deadline) {
+                (Some(&&at), Some(deadline)) if deadline < at => {
+                    if debouncer.poll(clock.at(deadline)) {
+                        runs.push(deadline);
+                    }
+                }
+                (Some(&&at), _) => {
+                    notifications.next();
+                    if debouncer.notify(clock.at(at)) {
+                        runs.push(at);
+                    }
+                }
+                (None, Some(deadline)) => {
+                    if debouncer.poll(clock.at(deadline)) {
+                        runs.push(deadline);
+                    }
+                }
+                (None, None) => return runs,
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_runs_once_after_quiet_period() {
+        let debouncer = debouncer(Edge::Trailing, None);
+
+        assert_eq!(runs(debouncer.clone(), &[0, 10, 20, 35]), [65]);
+        assert_eq!(runs(debouncer, &[0, 100]), [30, 130]);
+    }
+
+    #[test]
+    fn leading_runs_immediately_and_ignores_the_rest() {
+        let debouncer = debouncer(Edge::Leading, Some(50));
+
+        assert_eq!(runs(debouncer.clone(), &[0, 10, 20, 35]), [0]);
+        // 通知が途切れた後は再び即座に実行する
+        assert_eq!(runs(debouncer, &[0, 10, 100]), [0, 100]);
+    }
+
+    #[test]
+    fn both_edges_settle_on_the_last_notification() {
+        let debouncer = debouncer(Edge::Both, None);
+
+        assert_eq!(runs(debouncer.clone(), &[0]), [0]);
+        assert_eq!(runs(debouncer, &[0, 10, 20]), [0, 50]);
+    }
+
+    #[test]
+    fn max_wait_runs_during_a_long_burst() {
+        let notifications: Vec<u64> = (0..=120).step_by(20).collect();
+
+        assert_eq!(
+            runs(debouncer(Edge::Trailing, Some(100)), &notifications),
+            [100, 150]
+        );
+        assert_eq!(
+            runs(debouncer(Edge::Both, Some(100)), &notifications),
+            [0, 100, 150]
+        );
+        assert_eq!(runs(debouncer(Edge::Trailing, None), &notifications), [150]);
+    }
+
+    #[test]
+    fn poll_before_deadline_does_nothing() {
+        let clock = FakeClock::new();
+        let mut debouncer = debouncer(Edge::Trailing, None);
+
+        assert!(!debouncer.poll(clock.at(0)));
+        assert!(!debouncer.notify(clock.at(0)));
+        assert!(!debouncer.poll(clock.at(29)));
+        assert!(debouncer.poll(clock.at(30)));
+        assert_eq!(debouncer.deadline(), None);
+    }
+}
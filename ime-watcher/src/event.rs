@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use crate::ImeState;
+
+/// 監視スレッドから購読者に送られるイベント。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImeEvent {
+    /// 入力メソッドが変更された。
+    Changed(ImeState),
+    /// バックエンドが終了した。再起動されると`BackendRecovered`が送られる。
+    BackendLost,
+    /// バックエンドに再接続した。
+    BackendRecovered,
+    /// 入力メソッドのグループが切り替わったか、その内容が変更された(fcitx5のみ)。現在のグループ名
+    GroupChanged(String),
+}
+
+/// 現在の状態を保持し、購読者へイベントを配信する。
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    state: Mutex<Option<ImeState>>,
+    subscribers: Mutex<Vec<Sender<ImeEvent>>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<ImeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn current_state(&self) -> Option<ImeState> {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// 状態を更新して`ImeEvent::Changed`を配信する。
+    pub fn publish_state(&self, state: ImeState) {
+        *self.state.lock().unwrap() = Some(state.clone());
+        self.send(ImeEvent::Changed(state));
+    }
+
+    /// 切断された購読者はここで取り除かれる。
+    pub fn send(&self, event: ImeEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    /// 全ての購読者を切断する。
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 監視スレッドに停止を要求するためのハンドル。
+///
+/// シグナルハンドラなど別のスレッドから停止させる場合に用いる。停止後は購読中の
+/// Receiverが切断されるため、スレッドの終了を待つには`ImeWatcher::stop`を呼ぶ。
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    running: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_running(&self) {
+        self.running.store(true, Ordering::SeqCst);
+    }
+}
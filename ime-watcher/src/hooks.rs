@@ -0,0 +1,508 @@
+//! 入力メソッドの切り替え時にユーザーのコマンドを実行する。
+//!
+//! `$XDG_CONFIG_HOME/ime-watcher/hooks.toml`に次のように書く。
+//!
+//! ```toml
+//! [[hook]]
+//! # `sh -c`で実行する
+//! command = "notify-send IME \"$IME_ENGINE\""
+//! # 以下の条件は全て省略できる。指定したものが全て一致した場合に実行する
+//! from = "keyboard-us"
+//! to = "mozc"
+//! # 変換入力(`ImeState::is_active`)への切り替え: "to_composing", 直接入力へ: "to_direct"
+//! transition = "to_composing"
+//! # 既定は5000ms
+//! timeout_ms = 1000
+//! # 前回の実行からこの間隔が空くまで実行しない。既定は0
+//! min_interval_ms = 200
+//! ```
+//!
+//! コマンドには`IME_ENGINE`, `IME_KIND`, `IME_LANGUAGE`, `IME_DISPLAY_NAME`, `IME_SYMBOL`,
+//! `IME_OPEN`, `IME_ACTIVE`と、直前の状態があれば`IME_PREVIOUS_ENGINE`, `IME_PREVIOUS_KIND`が渡される。
+
+use serde::Deserialize;
+
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::config::config_dir;
+use crate::{ImeEvent, ImeState};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// タイムアウトを確認する間隔
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 変換入力と直接入力の間の切り替え
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    ToComposing,
+    ToDirect,
+}
+
+/// 設定ファイルの各フック
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub command: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub transition: Option<Transition>,
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub min_interval_ms: u64,
+}
+
+impl Hook {
+    /// 直前の状態`previous`から`current`への変更で実行するか
+    pub fn matches(&self, previous: Option<&ImeState>, current: &ImeState) -> bool {
+        if let Some(from) = &self.from
+            && previous.is_none_or(|previous| &previous.engine != from)
+        {
+            return false;
+        }
+
+        if let Some(to) = &self.to
+            && &current.engine != to
+        {
+            return false;
+        }
+
+        match self.transition {
+            Some(transition) => {
+                // 最初の状態は切り替えとはみなさない
+                let Some(previous) = previous else {
+                    return false;
+                };
+
+                match transition {
+                    Transition::ToComposing => !previous.is_active() && current.is_active(),
+                    Transition::ToDirect => previous.is_active() && !current.is_active(),
+                }
+            }
+            None => true,
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout_ms
+            .map_or(DEFAULT_TIMEOUT, Duration::from_millis)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HooksFile {
+    #[serde(default)]
+    hook: Vec<Hook>,
+}
+
+#[derive(Debug)]
+pub enum HooksError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl std::fmt::Display for HooksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HooksError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            HooksError::Parse(e) => write!(f, "invalid hooks: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HooksError {}
+
+/// フックの実行に失敗した理由
+#[derive(Debug)]
+pub enum HookError {
+    Spawn(String, std::io::Error),
+    Failed(String, ExitStatus),
+    /// 時間内に終了しなかったため強制終了した
+    TimedOut(String, Duration),
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookError::Spawn(command, e) => write!(f, "failed to run hook `{command}`: {e}"),
+            HookError::Failed(command, status) => write!(f, "hook `{command}` failed: {status}"),
+            HookError::TimedOut(command, timeout) => {
+                write!(f, "hook `{command}` timed out after {timeout:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+type FailureHandler = Arc<dyn Fn(HookError) + Send + Sync>;
+
+/// 実行の状態を加えたフック
+struct Entry {
+    hook: Hook,
+    last_run: Mutex<Option<Instant>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Entry {
+    /// 実行中でなく、前回から間隔が空いていれば実行を予約する。
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut last_run = self.last_run.lock().unwrap();
+
+        let interval = Duration::from_millis(self.hook.min_interval_ms);
+        if last_run.is_some_and(|last_run| now.duration_since(last_run) < interval) {
+            return false;
+        }
+
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        *last_run = Some(now);
+        true
+    }
+}
+
+/// 状態の変更を受け取り、一致するフックを別スレッドで実行する。
+pub struct Hooks {
+    entries: Vec<Entry>,
+    previous: Option<ImeState>,
+    on_failure: FailureHandler,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Hooks {
+    /// 失敗は標準エラー出力に書く。
+    pub fn new(hooks: Vec<Hook>) -> Self {
+        Self {
+            entries: hooks
+                .into_iter()
+                .map(|hook| Entry {
+                    hook,
+                    last_run: Mutex::new(None),
+                    running: Arc::new(AtomicBool::new(false)),
+                })
+                .collect(),
+            previous: None,
+            on_failure: Arc::new(|e| eprintln!("{e}")),
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/ime-watcher/hooks.toml`
+    pub fn user_hooks_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("ime-watcher").join("hooks.toml"))
+    }
+
+    /// ユーザーの設定ファイルが存在すれば読み込む。
+    pub fn from_user_config() -> Result<Self, HooksError> {
+        match Self::user_hooks_path() {
+            Some(path) if path.exists() => Self::from_file(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, HooksError> {
+        let path = path.as_ref();
+        let toml =
+            std::fs::read_to_string(path).map_err(|e| HooksError::Io(path.to_path_buf(), e))?;
+
+        Self::from_toml(&toml)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, HooksError> {
+        let file: HooksFile = toml::from_str(toml).map_err(HooksError::Parse)?;
+
+        Ok(Self::new(file.hook))
+    }
+
+    /// 失敗の通知先を差し替える。
+    pub fn with_failure_handler(
+        mut self,
+        on_failure: impl Fn(HookError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_failure = Arc::new(on_failure);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 一致するフックを実行する。待つ必要がなければ返り値のスレッドは捨ててよい
+    pub fn handle(&mut self, event: &ImeEvent) -> Vec<JoinHandle<()>> {
+        let ImeEvent::Changed(current) = event else {
+            return Vec::new();
+        };
+
+        let now = Instant::now();
+        let previous = self.previous.replace(current.clone());
+
+        // ハートビートなどによる同じ状態の再送は切り替えではない
+        if previous.as_ref() == Some(current) {
+            return Vec::new();
+        }
+
+        self.entries
+            .iter()
+            .filter(|entry| entry.hook.matches(previous.as_ref(), current))
+            .filter(|entry| entry.try_acquire(now))
+            .map(|entry| {
+                let command = state_command(&entry.hook.command, previous.as_ref(), current);
+                let hook_command = entry.hook.command.clone();
+                let timeout = entry.hook.timeout();
+                let running = entry.running.clone();
+                let on_failure = self.on_failure.clone();
+
+                std::thread::spawn(move || {
+                    if let Err(e) = run(command, hook_command, timeout) {
+                        on_failure(e);
+                    }
+
+                    running.store(false, Ordering::SeqCst);
+                })
+            })
+            .collect()
+    }
+}
+
+/// 状態を環境変数に入れたコマンド
+fn state_command(command: &str, previous: Option<&ImeState>, current: &ImeState) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .env("IME_ENGINE", &current.engine)
+        .env("IME_KIND", current.kind.to_string())
+        .env(
+            "IME_LANGUAGE",
+            current.language.as_deref().unwrap_or_default(),
+        )
+        .env(
+            "IME_DISPLAY_NAME",
+            current.display_name.as_deref().unwrap_or_default(),
+        )
+        .env("IME_SYMBOL", current.symbol.as_deref().unwrap_or_default())
+        .env(
+            "IME_OPEN",
+            current
+                .open
+                .map(|open| open.to_string())
+                .unwrap_or_default(),
+        )
+        .env("IME_ACTIVE", current.is_active().to_string());
+
+    if let Some(previous) = previous {
+        cmd.env("IME_PREVIOUS_ENGINE", &previous.engine)
+            .env("IME_PREVIOUS_KIND", previous.kind.to_string());
+    }
+
+    cmd
+}
+
+/// 終了を待ち、時間内に終わらなければ強制終了する。
+///
+/// `sh -c`の子プロセスも終了させるため、コマンドを新しいプロセスグループで実行してグループごと終了させる。
+fn run(mut command: Command, hook_command: String, timeout: Duration) -> Result<(), HookError> {
+    let mut child = command
+        .process_group(0)
+        .spawn()
+        .map_err(|e| HookError::Spawn(hook_command.clone(), e))?;
+
+    let started = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(HookError::Failed(hook_command, status)),
+            Ok(None) if started.elapsed() >= timeout => {
+                // プロセスグループのIDは`sh`のプロセスID
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+
+                return Err(HookError::TimedOut(hook_command, timeout));
+            }
+            Ok(None) => std::thread::sleep(WAIT_INTERVAL),
+            Err(e) => return Err(HookError::Spawn(hook_command, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputKind;
+
+    fn direct(engine: &str) -> ImeState {
+        ImeState::new(engine).with_kind(InputKind::Direct)
+    }
+
+    fn composing(engine: &str) -> ImeState {
+        ImeState::new(engine).with_kind(InputKind::Composing)
+    }
+
+    fn hook(toml: &str) -> Hook {
+        let mut file: HooksFile = toml::from_str(toml).unwrap();
+        file.hook.remove(0)
+    }
+
+    fn join_all(handles: Vec<JoinHandle<()>>) -> usize {
+        let count = handles.len();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        count
+    }
+
+    #[test]
+    fn matches_engines_and_transitions() {
+        let to_mozc = hook("[[hook]]\ncommand = \"true\"\nfrom = \"keyboard-us\"\nto = \"mozc\"");
+
+        assert!(to_mozc.matches(Some(&direct("keyboard-us")), &composing("mozc")));
+        assert!(!to_mozc.matches(None, &composing("mozc")));
+        assert!(!to_mozc.matches(Some(&direct("keyboard-de")), &composing("mozc")));
+
+        let to_direct = hook("[[hook]]\ncommand = \"true\"\ntransition = \"to_direct\"");
+
+        assert!(to_direct.matches(Some(&composing("mozc")), &direct("keyboard-us")));
+        assert!(to_direct.matches(
+            Some(&composing("mozc").with_open(true)),
+            &composing("mozc").with_open(false)
+        ));
+        assert!(!to_direct.matches(Some(&direct("keyboard-de")), &direct("keyboard-us")));
+        assert!(!to_direct.matches(None, &direct("keyboard-us")));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(matches!(
+            Hooks::from_toml("[[hook]]\ncommand = \"true\"\nwhen = \"always\""),
+            Err(HooksError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn runs_command_with_state_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+
+        let mut hooks = Hooks::new(vec![Hook {
+            command: format!(
+                "echo \"$IME_PREVIOUS_ENGINE>$IME_ENGINE $IME_KIND $IME_ACTIVE\" >> {}",
+                output.display()
+            ),
+            from: None,
+            to: None,
+            transition: None,
+            timeout_ms: None,
+            min_interval_ms: 0,
+        }]);
+
+        join_all(hooks.handle(&ImeEvent::Changed(direct("keyboard-us"))));
+        assert_eq!(join_all(hooks.handle(&ImeEvent::BackendLost)), 0);
+        join_all(hooks.handle(&ImeEvent::Changed(composing("mozc"))));
+        assert_eq!(
+            join_all(hooks.handle(&ImeEvent::Changed(composing("mozc")))),
+            0
+        );
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            ">keyboard-us direct false\nkeyboard-us>mozc composing true\n"
+        );
+    }
+
+    #[test]
+    fn failures_and_timeouts_are_reported() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+
+        let mut hooks = Hooks::from_toml(
+            "[[hook]]\ncommand = \"exit 3\"\n\n[[hook]]\ncommand = \"sleep 5\"\ntimeout_ms = 50",
+        )
+        .unwrap()
+        .with_failure_handler({
+            let failures = failures.clone();
+            move |e| failures.lock().unwrap().push(e)
+        });
+
+        let started = Instant::now();
+        join_all(hooks.handle(&ImeEvent::Changed(direct("keyboard-us"))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert!(
+            failures
+                .iter()
+                .any(|e| matches!(e, HookError::Failed(_, status) if status.code() == Some(3)))
+        );
+        assert!(
+            failures
+                .iter()
+                .any(|e| matches!(e, HookError::TimedOut(command, _) if command == "sleep 5"))
+        );
+    }
+
+    #[test]
+    fn timeouts_kill_the_whole_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+
+        let mut hooks = Hooks::from_toml(&format!(
+            "[[hook]]\ncommand = \"sleep 5 & echo $! > '{}'; wait\"\ntimeout_ms = 200",
+            pid_file.display()
+        ))
+        .unwrap();
+
+        join_all(hooks.handle(&ImeEvent::Changed(direct("keyboard-us"))));
+
+        // 終了したプロセスは一覧にないか、回収待ちのゾンビとなる
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let ps = Command::new("ps")
+            .args(["-o", "stat=", "-p", pid.trim()])
+            .output()
+            .unwrap();
+        let stat = String::from_utf8_lossy(&ps.stdout);
+        assert!(stat.trim().is_empty() || stat.starts_with('Z'), "{stat}");
+    }
+
+    #[test]
+    fn rate_limited_and_not_overlapping() {
+        let mut hooks =
+            Hooks::from_toml("[[hook]]\ncommand = \"sleep 0.2\"\nmin_interval_ms = 0").unwrap();
+
+        // 実行中は重ねて実行しない
+        let first = hooks.handle(&ImeEvent::Changed(direct("keyboard-us")));
+        assert_eq!(hooks.handle(&ImeEvent::Changed(composing("mozc"))).len(), 0);
+        join_all(first);
+        assert_eq!(
+            join_all(hooks.handle(&ImeEvent::Changed(direct("keyboard-us")))),
+            1
+        );
+
+        let mut hooks =
+            Hooks::from_toml("[[hook]]\ncommand = \"true\"\nmin_interval_ms = 60000").unwrap();
+
+        assert_eq!(
+            join_all(hooks.handle(&ImeEvent::Changed(direct("keyboard-us")))),
+            1
+        );
+        assert_eq!(
+            join_all(hooks.handle(&ImeEvent::Changed(composing("mozc")))),
+            0
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Linux/Windows/MacOS向けのIME検知ライブラリ。
+
+pub mod app_memory;
+pub mod change;
+pub mod classify;
+pub mod config;
+pub mod debounce;
+pub mod event;
+pub mod handle;
+#[cfg(unix)]
+pub mod hooks;
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(unix)]
+pub mod socket;
+pub mod state;
+
+pub use change::{ChangeDetector, ChangeFilter, EmitPolicy};
+pub use classify::EngineClassifier;
+pub use config::{Config, ConfigError};
+pub use event::{Broadcaster, ImeEvent};
+pub use handle::StopHandle;
+pub use state::{ImeState, InputKind};
+
+use std::sync::mpsc::Receiver;
+
+/// プラットフォームに依存しないIME監視のインターフェース。
+pub trait ImeWatcher {
+    type Error: std::error::Error + Send + 'static;
+
+    /// 監視を開始する。監視自体はバックグラウンドのスレッドで行われる。
+    fn start(&mut self) -> Result<(), Self::Error>;
+
+    /// 監視を停止し、スレッドの終了を待つ。
+    fn stop(&mut self) -> Result<(), Self::Error>;
+
+    /// 別のスレッドから停止を要求するためのハンドル。
+    fn stop_handle(&self) -> StopHandle;
+
+    /// 最後に取得した状態。
+    fn current_state(&self) -> Option<ImeState>;
+
+    /// 変更を購読する。監視が終了するとReceiverは切断される。
+    fn subscribe(&self) -> Receiver<ImeEvent>;
+}
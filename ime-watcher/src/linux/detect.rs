@@ -0,0 +1,260 @@
+//! 起動している入力メソッドフレームワークを判定する。
+//!
+//! `GTK_IM_MODULE`/`QT_IM_MODULE`/`XMODIFIERS`の指定と、セッションバス上の
+//! `org.fcitx.Fcitx5`/`org.freedesktop.IBus`の所有者、IBusのアドレスファイルから決める。
+
+use dbus::blocking::Connection;
+
+use std::time::Duration;
+
+use super::ibus::address::AddressLookup;
+use super::{DbusSettings, ImeWatchError};
+
+/// Linuxのバックエンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Fcitx5,
+    Ibus,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Fcitx5 => write!(f, "fcitx5"),
+            Backend::Ibus => write!(f, "ibus"),
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fcitx5" => Ok(Backend::Fcitx5),
+            "ibus" => Ok(Backend::Ibus),
+            other => Err(format!("unknown backend `{other}`")),
+        }
+    }
+}
+
+/// 判定の材料。テストでは直接組み立てる。
+#[derive(Debug, Clone, Default)]
+pub struct Evidence {
+    /// `GTK_IM_MODULE`
+    pub gtk_im_module: Option<String>,
+    /// `QT_IM_MODULE`
+    pub qt_im_module: Option<String>,
+    /// `XMODIFIERS`
+    pub xmodifiers: Option<String>,
+    /// セッションバスに接続できたか
+    pub session_bus: bool,
+    /// `org.fcitx.Fcitx5`に所有者がいるか
+    pub fcitx5_owned: bool,
+    /// `org.freedesktop.IBus`に所有者がいるか
+    pub ibus_owned: bool,
+    /// IBusのアドレスファイルが存在し、ibus-daemonが生きているか
+    pub ibus_address: bool,
+}
+
+fn non_empty_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn has_owner(conn: &Connection, name: &str, timeout: Duration) -> bool {
+    let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+
+    proxy
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (name,))
+        .is_ok_and(|(owned,): (bool,)| owned)
+}
+
+impl Evidence {
+    /// 環境変数、セッションバス、アドレスファイルから集める。
+    pub fn collect(settings: &DbusSettings) -> Self {
+        let conn = Connection::new_session().ok();
+
+        Self {
+            gtk_im_module: non_empty_var("GTK_IM_MODULE"),
+            qt_im_module: non_empty_var("QT_IM_MODULE"),
+            xmodifiers: non_empty_var("XMODIFIERS"),
+            session_bus: conn.is_some(),
+            fcitx5_owned: conn
+                .as_ref()
+                .is_some_and(|conn| has_owner(conn, "org.fcitx.Fcitx5", settings.timeout)),
+            ibus_owned: conn
+                .as_ref()
+                .is_some_and(|conn| has_owner(conn, "org.freedesktop.IBus", settings.timeout)),
+            ibus_address: AddressLookup::from_env().resolve().is_ok(),
+        }
+    }
+
+    /// 環境変数が指しているバックエンド(変数名, 値, バックエンド)
+    fn env_hints(&self) -> Vec<(&'static str, &str, Backend)> {
+        let module = |value: &str| match value {
+            "fcitx" | "fcitx5" => Some(Backend::Fcitx5),
+            "ibus" => Some(Backend::Ibus),
+            _ => None,
+        };
+
+        let vars = [
+            ("GTK_IM_MODULE", &self.gtk_im_module),
+            ("QT_IM_MODULE", &self.qt_im_module),
+        ];
+
+        let mut hints: Vec<_> = vars
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = value.as_deref()?;
+                Some((key, value, module(value)?))
+            })
+            .collect();
+
+        if let Some(xmodifiers) = self.xmodifiers.as_deref()
+            && let Some(backend) = xmodifiers.strip_prefix("@im=").and_then(module)
+        {
+            hints.push(("XMODIFIERS", xmodifiers, backend));
+        }
+
+        hints
+    }
+
+    /// バックエンドを決める。どちらも起動していない場合はエラー
+    pub fn decide(&self) -> Result<Detection, ImeWatchError> {
+        let mut reasons = Vec::new();
+
+        if !self.session_bus {
+            reasons.push("session bus is not available".to_owned());
+        }
+
+        let hints = self.env_hints();
+        for (key, value, _) in &hints {
+            reasons.push(format!("{key}={value}"));
+        }
+
+        if self.fcitx5_owned {
+            reasons.push("org.fcitx.Fcitx5 is owned on the session bus".to_owned());
+        }
+        if self.ibus_owned {
+            reasons.push("org.freedesktop.IBus is owned on the session bus".to_owned());
+        }
+        if self.ibus_address {
+            reasons.push("ibus-daemon of the IBus address file is running".to_owned());
+        }
+
+        let fcitx5_running = self.fcitx5_owned;
+        let ibus_running = self.ibus_owned || self.ibus_address;
+
+        let backend = match (fcitx5_running, ibus_running) {
+            (true, false) => Backend::Fcitx5,
+            (false, true) => Backend::Ibus,
+            (true, true) => {
+                let votes = |backend| hints.iter().filter(|(.., b)| *b == backend).count();
+
+                // fcitx5はIBusのフロントエンドとしても振る舞うため、決め手がなければfcitx5とする
+                if votes(Backend::Ibus) > votes(Backend::Fcitx5) {
+                    reasons.push("both are running, environment variables prefer ibus".to_owned());
+                    Backend::Ibus
+                } else {
+                    reasons.push("both are running, preferring fcitx5".to_owned());
+                    Backend::Fcitx5
+                }
+            }
+            (false, false) => {
+                reasons.push("neither fcitx5 nor ibus-daemon is running".to_owned());
+                return Err(ImeWatchError::BackendNotRunning(reasons.join(", ")));
+            }
+        };
+
+        Ok(Detection { backend, reasons })
+    }
+}
+
+/// 判定結果と、その理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub backend: Backend,
+    pub reasons: Vec<String>,
+}
+
+impl std::fmt::Display for Detection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.backend, self.reasons.join(", "))
+    }
+}
+
+/// 現在の環境で用いるバックエンドを判定する。
+pub fn detect_backend() -> Result<Detection, ImeWatchError> {
+    Evidence::collect(&DbusSettings::default()).decide()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running() -> Evidence {
+        Evidence {
+            session_bus: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn single_running_backend_wins_over_env() {
+        let evidence = Evidence {
+            gtk_im_module: Some("ibus".to_owned()),
+            fcitx5_owned: true,
+            ..running()
+        };
+
+        assert_eq!(evidence.decide().unwrap().backend, Backend::Fcitx5);
+
+        let evidence = Evidence {
+            ibus_address: true,
+            ..running()
+        };
+
+        assert_eq!(evidence.decide().unwrap().backend, Backend::Ibus);
+    }
+
+    #[test]
+    fn env_breaks_tie() {
+        let evidence = Evidence {
+            gtk_im_module: Some("ibus".to_owned()),
+            xmodifiers: Some("@im=ibus".to_owned()),
+            qt_im_module: Some("fcitx".to_owned()),
+            fcitx5_owned: true,
+            ibus_owned: true,
+            ..running()
+        };
+
+        let detection = evidence.decide().unwrap();
+        assert_eq!(detection.backend, Backend::Ibus);
+        assert!(
+            detection
+                .reasons
+                .contains(&"XMODIFIERS=@im=ibus".to_owned())
+        );
+
+        let evidence = Evidence {
+            fcitx5_owned: true,
+            ibus_owned: true,
+            ..running()
+        };
+
+        assert_eq!(evidence.decide().unwrap().backend, Backend::Fcitx5);
+    }
+
+    #[test]
+    fn nothing_running_is_error() {
+        let evidence = Evidence {
+            gtk_im_module: Some("fcitx".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            evidence.decide(),
+            Err(ImeWatchError::BackendNotRunning(_))
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Linuxのバックエンドで共通に用いるエラー。
+
+use super::ibus::address::AddressError;
+use crate::InputKind;
+
+/// 監視の開始・継続に失敗した理由
+#[derive(Debug)]
+pub enum ImeWatchError {
+    /// セッションバスに接続できない
+    NoSessionBus(dbus::Error),
+    /// fcitx5・ibus-daemonが起動していない
+    BackendNotRunning(String),
+    /// バックエンドの応答やシグナルが想定した形式ではない
+    ProtocolMismatch(String),
+    /// バスとの接続が切断された
+    Disconnected(dbus::Error),
+    /// バックエンドが応答しない
+    Timeout(dbus::Error),
+    /// 公開しようとしたバス名が既に使われている
+    NameTaken(String),
+    /// 切り替え先のエンジンがバックエンドに存在しない
+    UnknownEngine(String),
+    /// 切り替え先となる種類のエンジンをまだ使っておらず、有効にされてもいない
+    NoEngineToSwitch(InputKind),
+    /// バスとイベントの待ち合わせに失敗した
+    Io(std::io::Error),
+}
+
+impl ImeWatchError {
+    /// バックエンドの起動・再起動を待てば回復する見込みがあるか
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ImeWatchError::BackendNotRunning(_)
+                | ImeWatchError::Disconnected(_)
+                | ImeWatchError::Timeout(_)
+        )
+    }
+}
+
+impl std::fmt::Display for ImeWatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImeWatchError::NoSessionBus(e) => {
+                write!(f, "failed to connect to the session bus: {e}")
+            }
+            ImeWatchError::BackendNotRunning(reason) => {
+                write!(f, "backend is not running: {reason}")
+            }
+            ImeWatchError::ProtocolMismatch(reason) => write!(f, "unexpected response: {reason}"),
+            ImeWatchError::Disconnected(e) => write!(f, "disconnected from the bus: {e}"),
+            ImeWatchError::Timeout(e) => write!(f, "backend did not respond: {e}"),
+            ImeWatchError::NameTaken(name) => write!(f, "{name} is already owned"),
+            ImeWatchError::UnknownEngine(engine) => write!(f, "unknown engine `{engine}`"),
+            ImeWatchError::NoEngineToSwitch(kind) => {
+                write!(
+                    f,
+                    "no {kind} engine has been used or is active, set one by name first"
+                )
+            }
+            ImeWatchError::Io(e) => write!(f, "failed to wait for events: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ImeWatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImeWatchError::NoSessionBus(e)
+            | ImeWatchError::Disconnected(e)
+            | ImeWatchError::Timeout(e) => Some(e),
+            ImeWatchError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// D-Busのエラー名から分類する。
+impl From<dbus::Error> for ImeWatchError {
+    fn from(e: dbus::Error) -> Self {
+        match e.name() {
+            Some(
+                "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner",
+            ) => ImeWatchError::BackendNotRunning(e.to_string()),
+            Some(
+                "org.freedesktop.DBus.Error.UnknownMethod"
+                | "org.freedesktop.DBus.Error.UnknownObject"
+                | "org.freedesktop.DBus.Error.UnknownInterface"
+                | "org.freedesktop.DBus.Error.UnknownProperty"
+                | "org.freedesktop.DBus.Error.InvalidArgs"
+                | "org.freedesktop.DBus.Error.InvalidSignature",
+            ) => ImeWatchError::ProtocolMismatch(e.to_string()),
+            Some(
+                "org.freedesktop.DBus.Error.NoReply"
+                | "org.freedesktop.DBus.Error.Timeout"
+                | "org.freedesktop.DBus.Error.TimedOut",
+            ) => ImeWatchError::Timeout(e),
+            // 名前のないエラーや`Disconnected`, `NoServer`などはソケットの問題として扱う
+            _ => ImeWatchError::Disconnected(e),
+        }
+    }
+}
+
+impl From<AddressError> for ImeWatchError {
+    fn from(e: AddressError) -> Self {
+        ImeWatchError::BackendNotRunning(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(name: &str) -> ImeWatchError {
+        dbus::Error::new_custom(name, "test").into()
+    }
+
+    #[test]
+    fn classifies_by_error_name() {
+        assert!(matches!(
+            classify("org.freedesktop.DBus.Error.ServiceUnknown"),
+            ImeWatchError::BackendNotRunning(_)
+        ));
+        assert!(matches!(
+            classify("org.freedesktop.DBus.Error.UnknownMethod"),
+            ImeWatchError::ProtocolMismatch(_)
+        ));
+        assert!(matches!(
+            classify("org.freedesktop.DBus.Error.NoReply"),
+            ImeWatchError::Timeout(_)
+        ));
+        assert!(matches!(
+            classify("org.freedesktop.DBus.Error.Disconnected"),
+            ImeWatchError::Disconnected(_)
+        ));
+    }
+
+    #[test]
+    fn protocol_mismatch_is_not_transient() {
+        assert!(classify("org.freedesktop.DBus.Error.NoReply").is_transient());
+        assert!(!classify("org.freedesktop.DBus.Error.InvalidArgs").is_transient());
+    }
+}
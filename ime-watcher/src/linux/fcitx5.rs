@@ -0,0 +1,1016 @@
+use dbus::arg::{AppendAll, PropMap, ReadAll};
+use dbus::blocking::{Proxy, SyncConnection, stdintf::org_freedesktop_dbus::Properties};
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::MatchRule;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::focus::parse_debug_info;
+use super::{DbusSettings, ImeWatchError};
+use crate::debounce::{DebounceSettings, Debouncer};
+use crate::{
+    Broadcaster, ChangeFilter, EngineClassifier, ImeEvent, ImeState, ImeWatcher, StopHandle,
+};
+
+/// タイミングの通知用
+struct GetInputMethod;
+
+pub(crate) const FCITX5_BUS_NAME: &str = "org.fcitx.Fcitx5";
+pub(crate) const CONTROLLER_INTERFACE: &str = "org.fcitx.Fcitx.Controller1";
+pub(crate) const SNI_WATCHER_BUS_NAME: &str = "org.kde.StatusNotifierWatcher";
+/// kimpanelのパネル。これがある場合にのみfcitx5はkimpanelのシグナルを送る
+pub(crate) const KIMPANEL_BUS_NAME: &str = "org.kde.impanel";
+
+/// 切り替えを知らせるシグナルが届かない場合に`CurrentInputMethod`を確認する間隔
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// fcitx5の変更を検知する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fcitx5Mode {
+    /// fcitx5自身のシグナルを購読し、StatusNotifierItemがあれば`NewIcon`も併用する。
+    /// kimpanelのパネルもStatusNotifierItemもなければ`CurrentInputMethod`を定期的に確認する
+    #[default]
+    Auto,
+    /// fcitx5自身のシグナルのみを購読する。トレイのない環境向け。
+    /// kimpanelのパネルがなければ`CurrentInputMethod`を定期的に確認する
+    Native,
+    /// StatusNotifierItemの`NewIcon`のみを購読する
+    StatusNotifier,
+}
+
+/// fcitx5のシグナルを契機に`CurrentInputMethod`を取得する。
+///
+/// fcitx5が再起動された場合は購読し直す。
+#[derive(Default)]
+pub struct Fcitx5Watcher {
+    broadcaster: Arc<Broadcaster>,
+    classifier: Arc<EngineClassifier>,
+    mode: Fcitx5Mode,
+    settings: DbusSettings,
+    stop_handle: StopHandle,
+    threads: Vec<JoinHandle<Result<(), ImeWatchError>>>,
+}
+
+impl Fcitx5Watcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// エンジン名の判定に用いる。
+    pub fn with_classifier(mut self, classifier: EngineClassifier) -> Self {
+        self.classifier = Arc::new(classifier);
+        self
+    }
+
+    /// 変更の検知方法を指定する。
+    pub fn with_mode(mut self, mode: Fcitx5Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// タイムアウトやStatusNotifierItemの`Id`を指定する。
+    pub fn with_settings(mut self, settings: DbusSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+}
+
+/// fcitx5のStatusNotifierItemを探す。見つからない場合は`None`
+fn find_fcitx5_sni(
+    conn: &SyncConnection,
+    settings: &DbusSettings,
+) -> Result<Option<(String, String)>, dbus::Error> {
+    let notifier_watcher_proxy = conn.with_proxy(
+        SNI_WATCHER_BUS_NAME,
+        "/StatusNotifierWatcher",
+        settings.timeout,
+    );
+
+    let notifier_items: Vec<String> = notifier_watcher_proxy.get(
+        "org.kde.StatusNotifierWatcher",
+        "RegisteredStatusNotifierItems",
+    )?;
+
+    for sni_name in notifier_items.into_iter() {
+        // `<バス名>@<パス>`の形式でないアイテムは飛ばす
+        let Some((dest, path)) = sni_name.split_once("@") else {
+            continue;
+        };
+
+        let sni_proxy = conn.with_proxy(dest, path, settings.timeout);
+
+        // 終了済みのアイテムが残っている場合があるため、取得できないものは飛ばす
+        let Ok(sni_id) = sni_proxy.get::<String>("org.kde.StatusNotifierItem", "Id") else {
+            continue;
+        };
+
+        if sni_id == settings.sni_id {
+            return Ok(Some((dest.to_owned(), path.to_owned())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// fcitx5の`/controller`
+fn controller_proxy(conn: &SyncConnection, timeout: Duration) -> Proxy<'_, &SyncConnection> {
+    conn.with_proxy(FCITX5_BUS_NAME, "/controller", timeout)
+}
+
+/// `AvailableInputMethods`の各要素
+/// (uniqueName, name, nativeName, icon, label, languageCode, configurable)
+type AvailableInputMethod = (String, String, String, String, String, String, bool);
+
+fn available_input_methods(
+    proxy: &Proxy<'_, &SyncConnection>,
+) -> Result<Vec<AvailableInputMethod>, dbus::Error> {
+    let (input_methods,): (Vec<AvailableInputMethod>,) =
+        proxy.method_call(CONTROLLER_INTERFACE, "AvailableInputMethods", ())?;
+
+    Ok(input_methods)
+}
+
+/// 利用可能な入力メソッドの一覧。
+pub fn available_engines(
+    classifier: &EngineClassifier,
+    settings: &DbusSettings,
+) -> Result<Vec<ImeState>, ImeWatchError> {
+    let conn = SyncConnection::new_session().map_err(ImeWatchError::NoSessionBus)?;
+
+    let input_methods = available_input_methods(&controller_proxy(&conn, settings.timeout))?;
+
+    Ok(input_methods
+        .into_iter()
+        .map(
+            |(unique_name, name, _native_name, _icon, label, language, _)| {
+                let mut state = classifier.state(unique_name).with_display_name(name);
+
+                if state.language.is_none() && !language.is_empty() {
+                    state = state.with_language(language);
+                }
+                if !label.is_empty() {
+                    state = state.with_symbol(label);
+                }
+
+                state
+            },
+        )
+        .collect())
+}
+
+/// fcitx5の入力メソッド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fcitx5InputMethod {
+    /// `mozc`, `keyboard-us`など。切り替えに用いる
+    pub unique_name: String,
+    pub display_name: String,
+    /// `ja`などの言語コード。ない場合は空文字列
+    pub language_code: String,
+    /// グループでのキーボードレイアウト。空文字列ならグループの既定
+    pub layout: String,
+    /// 設定画面があるか
+    pub configurable: bool,
+}
+
+impl From<AvailableInputMethod> for Fcitx5InputMethod {
+    fn from(
+        (unique_name, name, _native_name, _icon, _label, language_code, configurable): AvailableInputMethod,
+    ) -> Self {
+        Self {
+            unique_name,
+            display_name: name,
+            language_code,
+            layout: String::new(),
+            configurable,
+        }
+    }
+}
+
+/// 入力メソッドのグループ。切り替えの対象はグループ内の入力メソッドに限られる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fcitx5Group {
+    pub name: String,
+    pub default_layout: String,
+    pub input_methods: Vec<Fcitx5InputMethod>,
+}
+
+/// `FullInputMethodGroupInfo`の各要素
+/// (uniqueName, name, nativeName, icon, label, languageCode, addon, configurable, layout, properties)
+type FullGroupItem = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    String,
+    String,
+    bool,
+    String,
+    PropMap,
+);
+
+/// 入力コンテキストの状態(`State`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fcitx5State {
+    /// フォーカスされた入力コンテキストがない
+    NoInputContext,
+    /// 直接入力
+    Inactive,
+    /// 入力メソッドが有効
+    Active,
+}
+
+impl TryFrom<i32> for Fcitx5State {
+    type Error = ImeWatchError;
+
+    fn try_from(state: i32) -> Result<Self, Self::Error> {
+        match state {
+            0 => Ok(Fcitx5State::NoInputContext),
+            1 => Ok(Fcitx5State::Inactive),
+            2 => Ok(Fcitx5State::Active),
+            other => Err(ImeWatchError::ProtocolMismatch(format!(
+                "unknown fcitx5 state {other}"
+            ))),
+        }
+    }
+}
+
+impl Fcitx5State {
+    /// [`ImeState::open`]としての値。入力コンテキストがなければ分からない
+    pub fn open(self) -> Option<bool> {
+        match self {
+            Fcitx5State::NoInputContext => None,
+            Fcitx5State::Inactive => Some(false),
+            Fcitx5State::Active => Some(true),
+        }
+    }
+}
+
+/// `State`の値から[`ImeState::open`]を求める。未知の値は分からないものとする
+pub(crate) fn open_state(state: i32) -> Option<bool> {
+    Fcitx5State::try_from(state)
+        .ok()
+        .and_then(Fcitx5State::open)
+}
+
+/// `org.fcitx.Fcitx.Controller1`で入力メソッドを切り替える。
+///
+/// 操作の対象はフォーカスされている入力コンテキスト。
+pub struct Fcitx5Controller {
+    conn: SyncConnection,
+    timeout: Duration,
+}
+
+impl Fcitx5Controller {
+    /// セッションバスに接続する。fcitx5が起動していない場合は失敗する
+    pub fn connect(settings: &DbusSettings) -> Result<Self, ImeWatchError> {
+        let conn = SyncConnection::new_session().map_err(ImeWatchError::NoSessionBus)?;
+
+        if fcitx5_owner(&conn, settings.timeout).is_none() {
+            return Err(ImeWatchError::BackendNotRunning(
+                "fcitx5 is not running".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            conn,
+            timeout: settings.timeout,
+        })
+    }
+
+    fn call<A: AppendAll, R: ReadAll>(&self, method: &str, args: A) -> Result<R, ImeWatchError> {
+        Ok(controller_proxy(&self.conn, self.timeout).method_call(
+            CONTROLLER_INTERFACE,
+            method,
+            args,
+        )?)
+    }
+
+    /// 入力メソッドを有効にする。
+    pub fn activate(&self) -> Result<(), ImeWatchError> {
+        self.call("Activate", ())
+    }
+
+    /// 直接入力にする。
+    pub fn deactivate(&self) -> Result<(), ImeWatchError> {
+        self.call("Deactivate", ())
+    }
+
+    /// 有効と直接入力を切り替える。
+    pub fn toggle(&self) -> Result<(), ImeWatchError> {
+        self.call("Toggle", ())
+    }
+
+    /// フォーカスされた入力コンテキストの状態。オープン状態は[`Fcitx5State::open`]で得られる
+    pub fn state(&self) -> Result<Fcitx5State, ImeWatchError> {
+        let (state,): (i32,) = self.call("State", ())?;
+
+        state.try_into()
+    }
+
+    /// 現在の入力メソッドの名前
+    pub fn current_input_method(&self) -> Result<String, ImeWatchError> {
+        let (name,): (String,) = self.call("CurrentInputMethod", ())?;
+
+        Ok(name)
+    }
+
+    /// フォーカスされた入力コンテキストのプログラム名。フォーカスがないか名前がない場合は`None`
+    pub fn focused_program(&self) -> Result<Option<String>, ImeWatchError> {
+        let (debug_info,): (String,) = self.call("DebugInfo", ())?;
+
+        Ok(parse_debug_info(&debug_info))
+    }
+
+    /// インストールされている入力メソッド。`layout`は空文字列となる
+    pub fn input_methods(&self) -> Result<Vec<Fcitx5InputMethod>, ImeWatchError> {
+        let input_methods = available_input_methods(&controller_proxy(&self.conn, self.timeout))?;
+
+        Ok(input_methods.into_iter().map(Into::into).collect())
+    }
+
+    /// グループ名の一覧
+    pub fn group_names(&self) -> Result<Vec<String>, ImeWatchError> {
+        let (groups,): (Vec<String>,) = self.call("InputMethodGroups", ())?;
+
+        Ok(groups)
+    }
+
+    pub fn current_group(&self) -> Result<String, ImeWatchError> {
+        let (group,): (String,) = self.call("CurrentInputMethodGroup", ())?;
+
+        Ok(group)
+    }
+
+    /// グループとその入力メソッド
+    ///
+    /// `FullInputMethodGroupInfo`のない古いfcitx5では`InputMethodGroupInfo`と
+    /// `AvailableInputMethods`を突き合わせる。
+    pub fn group(&self, name: &str) -> Result<Fcitx5Group, ImeWatchError> {
+        let full: Result<(String, String, Vec<FullGroupItem>), _> =
+            self.call("FullInputMethodGroupInfo", (name,));
+
+        match full {
+            Ok((name, default_layout, items)) => Ok(Fcitx5Group {
+                name,
+                default_layout,
+                input_methods: items
+                    .into_iter()
+                    .map(
+                        |(
+                            unique_name,
+                            name,
+                            _,
+                            _,
+                            _,
+                            language_code,
+                            _,
+                            configurable,
+                            layout,
+                            _,
+                        )| {
+                            Fcitx5InputMethod {
+                                unique_name,
+                                display_name: name,
+                                language_code,
+                                layout,
+                                configurable,
+                            }
+                        },
+                    )
+                    .collect(),
+            }),
+            Err(ImeWatchError::ProtocolMismatch(_)) => self.group_from_available(name),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn group_from_available(&self, name: &str) -> Result<Fcitx5Group, ImeWatchError> {
+        let (default_layout, items): (String, Vec<(String, String)>) =
+            self.call("InputMethodGroupInfo", (name,))?;
+        let available = self.input_methods()?;
+
+        let input_methods = items
+            .into_iter()
+            .map(|(unique_name, layout)| {
+                match available.iter().find(|im| im.unique_name == unique_name) {
+                    Some(im) => Fcitx5InputMethod {
+                        layout,
+                        ..im.clone()
+                    },
+                    // 利用できなくなった入力メソッドも名前だけは返す
+                    None => Fcitx5InputMethod {
+                        display_name: unique_name.clone(),
+                        unique_name,
+                        language_code: String::new(),
+                        layout,
+                        configurable: false,
+                    },
+                }
+            })
+            .collect();
+
+        Ok(Fcitx5Group {
+            name: name.to_owned(),
+            default_layout,
+            input_methods,
+        })
+    }
+
+    /// 全てのグループ
+    pub fn groups(&self) -> Result<Vec<Fcitx5Group>, ImeWatchError> {
+        self.group_names()?
+            .iter()
+            .map(|name| self.group(name))
+            .collect()
+    }
+
+    /// 入力メソッドを切り替える。利用可能な入力メソッドにない名前は`UnknownEngine`
+    ///
+    /// fcitx5は未知の名前を黙って無視するため、先に一覧と照合する。
+    pub fn set_current_im(&self, name: &str) -> Result<(), ImeWatchError> {
+        let input_methods = available_input_methods(&controller_proxy(&self.conn, self.timeout))?;
+
+        if !input_methods
+            .iter()
+            .any(|(unique_name, ..)| unique_name == name)
+        {
+            return Err(ImeWatchError::UnknownEngine(name.to_owned()));
+        }
+
+        self.call("SetCurrentIM", (name,))
+    }
+}
+
+/// 通知を受け取ると`CurrentInputMethod`を取得して配信する。
+///
+/// 切り替え時にはシグナルが続けて届くため、まとめて取得する。グループ名が変わった場合と
+/// `groups_changed`が立っている場合は、先に`GroupChanged`を配信する。
+fn run_worker(
+    worker_conn: SyncConnection,
+    receiver: Receiver<GetInputMethod>,
+    filter: &ChangeFilter,
+    classifier: &EngineClassifier,
+    timeout: Duration,
+    debounce: DebounceSettings,
+    groups_changed: &AtomicBool,
+) -> Result<(), dbus::Error> {
+    let controller_proxy = controller_proxy(&worker_conn, timeout);
+
+    let get_state = || -> Result<ImeState, dbus::Error> {
+        let (ime_status,): (String,) =
+            controller_proxy.method_call(CONTROLLER_INTERFACE, "CurrentInputMethod", ())?;
+
+        let (state,): (i32,) = controller_proxy.method_call(CONTROLLER_INTERFACE, "State", ())?;
+
+        Ok(ImeState {
+            open: open_state(state),
+            ..classifier.state(ime_status)
+        })
+    };
+
+    let mut groups = GroupTracker::default();
+
+    Debouncer::new(debounce).run(&receiver, || {
+        let notified = groups_changed.swap(false, Ordering::SeqCst);
+
+        // fcitx5の再起動中は失敗するが、それは監視スレッド側で`BackendLost`として通知される
+        if let Ok((group,)) = controller_proxy.method_call::<(String,), _, _, _>(
+            CONTROLLER_INTERFACE,
+            "CurrentInputMethodGroup",
+            (),
+        ) && let Some(event) = groups.update(group, notified)
+        {
+            filter.send(event);
+        }
+
+        if let Ok(state) = get_state() {
+            filter.publish_state(state);
+        }
+    });
+
+    Ok(())
+}
+
+/// 最後のグループ名を覚え、グループの変更を判定する。
+#[derive(Debug, Default)]
+pub(crate) struct GroupTracker {
+    last: Option<String>,
+}
+
+impl GroupTracker {
+    /// 名前が変わったか`Controller1`から通知(`notified`)があれば`GroupChanged`
+    ///
+    /// 最初のグループ名は変更として扱わない。
+    pub(crate) fn update(&mut self, group: String, notified: bool) -> Option<ImeEvent> {
+        let changed = notified || self.last.as_ref().is_some_and(|last| *last != group);
+        self.last = Some(group.clone());
+
+        changed.then_some(ImeEvent::GroupChanged(group))
+    }
+}
+
+/// 監視対象が`previous`から`current`に入れ替わったときに配信するイベント
+pub(crate) fn backend_transition(previous: Option<&str>, current: Option<&str>) -> Vec<ImeEvent> {
+    if previous == current {
+        return Vec::new();
+    }
+
+    let lost = previous.map(|_| ImeEvent::BackendLost);
+    let recovered = current.map(|_| ImeEvent::BackendRecovered);
+
+    lost.into_iter().chain(recovered).collect()
+}
+
+/// StatusNotifierItemの`NewIcon`を購読する。
+fn match_new_icon(
+    conn: &SyncConnection,
+    (dest, path): &(String, String),
+    sender: Sender<GetInputMethod>,
+    timeout: Duration,
+) -> Result<Token, dbus::Error> {
+    let signal_mr = MatchRule::new_signal("org.kde.StatusNotifierItem", "NewIcon");
+
+    conn.with_proxy(dest, path, timeout).match_start(
+        signal_mr,
+        true,
+        Box::new(move |_message, _| {
+            let _ = sender.send(GetInputMethod);
+
+            true
+        }),
+    )
+}
+
+/// fcitx5自身が送るシグナル(インターフェース, メンバー)
+///
+/// 入力メソッドの切り替えはkimpanelのプロパティ更新として、グループの変更は`Controller1`から通知される。
+/// kimpanelのシグナルはkimpanelのパネル([`KIMPANEL_BUS_NAME`])がある場合にのみ送られる。
+pub(crate) const NATIVE_SIGNALS: [(&str, &str); 4] = [
+    (CONTROLLER_INTERFACE, "InputMethodGroupsChanged"),
+    ("org.kde.kimpanel.inputmethod", "UpdateProperty"),
+    ("org.kde.kimpanel.inputmethod", "RegisterProperties"),
+    ("org.kde.kimpanel.inputmethod", "Enable"),
+];
+
+/// fcitx5自身のシグナルを購読する。`Controller1`のシグナルでは`groups_changed`も立てる
+///
+/// 送信元はユニーク名になるため、ローカルの振り分けに合わせてwell-known名では絞り込まない。
+fn match_native_signals(
+    conn: &SyncConnection,
+    sender: &Sender<GetInputMethod>,
+    groups_changed: &Arc<AtomicBool>,
+) -> Result<Vec<Token>, dbus::Error> {
+    NATIVE_SIGNALS
+        .iter()
+        .map(|(interface, member)| {
+            let sender = sender.clone();
+            let groups_changed =
+                (*interface == CONTROLLER_INTERFACE).then(|| groups_changed.clone());
+
+            conn.add_match_no_cb(&MatchRule::new_signal(*interface, *member).match_str())?;
+            Ok(conn.start_receive(
+                MatchRule::new_signal(*interface, *member),
+                Box::new(move |_message, _| {
+                    if let Some(groups_changed) = &groups_changed {
+                        groups_changed.store(true, Ordering::SeqCst);
+                    }
+                    let _ = sender.send(GetInputMethod);
+
+                    true
+                }),
+            ))
+        })
+        .collect()
+}
+
+/// `name`を所有するユニーク名。所有されていない場合は`None`
+fn name_owner(conn: &SyncConnection, name: &str, timeout: Duration) -> Option<String> {
+    let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+
+    proxy
+        .method_call("org.freedesktop.DBus", "GetNameOwner", (name,))
+        .ok()
+        .map(|(owner,): (String,)| owner)
+}
+
+/// fcitx5のユニーク名。起動していない場合は`None`
+fn fcitx5_owner(conn: &SyncConnection, timeout: Duration) -> Option<String> {
+    name_owner(conn, FCITX5_BUS_NAME, timeout)
+}
+
+/// 入力メソッドの切り替えを知らせるシグナルが届かず、定期的に確認する必要があるか
+///
+/// `kimpanel`はkimpanelのパネルがあるか、`item`はStatusNotifierItemを購読しているか。
+pub(crate) fn needs_polling(mode: Fcitx5Mode, kimpanel: bool, item: bool) -> bool {
+    match mode {
+        Fcitx5Mode::Auto => !kimpanel && !item,
+        Fcitx5Mode::Native => !kimpanel,
+        // アイテムがなければ監視対象がないものとして扱う
+        Fcitx5Mode::StatusNotifier => false,
+    }
+}
+
+/// fcitx5・StatusNotifierWatcher・kimpanelのパネルの再起動、アイテムの登録・解除を監視する。
+/// コールバック内では購読を変更できないため、フラグを立てて監視ループ側で探し直す。
+fn match_backend_changes(
+    conn: &SyncConnection,
+    resync: &Arc<AtomicBool>,
+) -> Result<Vec<Token>, dbus::Error> {
+    let name_owner_mr = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+
+    let mut tokens = vec![conn.add_match(name_owner_mr, {
+        let resync = resync.clone();
+
+        move |(name, _old_owner, _new_owner): (String, String, String), _, _| {
+            if [FCITX5_BUS_NAME, SNI_WATCHER_BUS_NAME, KIMPANEL_BUS_NAME].contains(&name.as_str()) {
+                resync.store(true, Ordering::SeqCst);
+            }
+
+            true
+        }
+    })?];
+
+    for member in [
+        "StatusNotifierItemRegistered",
+        "StatusNotifierItemUnregistered",
+    ] {
+        let item_mr = MatchRule::new_signal(SNI_WATCHER_BUS_NAME, member);
+
+        tokens.push(conn.add_match(item_mr, {
+            let resync = resync.clone();
+
+            move |(_service,): (String,), _, _| {
+                resync.store(true, Ordering::SeqCst);
+
+                true
+            }
+        })?);
+    }
+
+    Ok(tokens)
+}
+
+/// 現在の購読状態
+struct Fcitx5Subscription {
+    mode: Fcitx5Mode,
+    settings: DbusSettings,
+    /// 監視対象のバックエンド。`StatusNotifier`ではアイテム、それ以外ではfcitx5のユニーク名
+    backend: Option<String>,
+    item: Option<(String, String)>,
+    new_icon_token: Option<Token>,
+    native_tokens: Vec<Token>,
+    sender: Sender<GetInputMethod>,
+    /// シグナルが届かないため`CurrentInputMethod`を確認しているか
+    polling: bool,
+    /// 最後に確認した入力メソッドと`State`
+    polled: Option<(String, i32)>,
+}
+
+impl Fcitx5Subscription {
+    fn new(
+        conn: &SyncConnection,
+        mode: Fcitx5Mode,
+        settings: DbusSettings,
+        sender: Sender<GetInputMethod>,
+        groups_changed: &Arc<AtomicBool>,
+    ) -> Result<Self, dbus::Error> {
+        // 送信元で絞り込まないため、fcitx5が再起動しても購読し直す必要はない
+        let native_tokens = match mode {
+            Fcitx5Mode::StatusNotifier => Vec::new(),
+            Fcitx5Mode::Auto | Fcitx5Mode::Native => {
+                match_native_signals(conn, &sender, groups_changed)?
+            }
+        };
+
+        Ok(Self {
+            mode,
+            settings,
+            backend: None,
+            item: None,
+            new_icon_token: None,
+            native_tokens,
+            sender,
+            polling: false,
+            polled: None,
+        })
+    }
+
+    /// StatusNotifierItemを探し直し、変化していれば購読し直す。
+    fn resync(&mut self, conn: &SyncConnection) -> Result<Vec<ImeEvent>, dbus::Error> {
+        if self.mode != Fcitx5Mode::Native {
+            // StatusNotifierWatcherが存在しない場合もアイテムがないものとして扱う
+            let item = find_fcitx5_sni(conn, &self.settings).unwrap_or(None);
+
+            if item != self.item {
+                if let Some(token) = self.new_icon_token.take() {
+                    conn.remove_match(token)?;
+                }
+
+                if let Some(item) = &item {
+                    self.new_icon_token = Some(match_new_icon(
+                        conn,
+                        item,
+                        self.sender.clone(),
+                        self.settings.timeout,
+                    )?);
+                }
+
+                self.item = item;
+            }
+        }
+
+        let backend = match self.mode {
+            Fcitx5Mode::StatusNotifier => self
+                .item
+                .as_ref()
+                .map(|(dest, path)| format!("{dest}@{path}")),
+            Fcitx5Mode::Auto | Fcitx5Mode::Native => fcitx5_owner(conn, self.settings.timeout),
+        };
+
+        let events = backend_transition(self.backend.as_deref(), backend.as_deref());
+
+        if events.last() == Some(&ImeEvent::BackendRecovered) {
+            // 再起動後の状態を取得し直す
+            let _ = self.sender.send(GetInputMethod);
+        }
+
+        self.backend = backend;
+
+        let kimpanel = name_owner(conn, KIMPANEL_BUS_NAME, self.settings.timeout).is_some();
+        self.polling =
+            self.backend.is_some() && needs_polling(self.mode, kimpanel, self.item.is_some());
+        self.polled = None;
+
+        Ok(events)
+    }
+
+    /// 入力メソッドか`State`が前回の確認から変わっていれば取得させる。
+    fn poll(&mut self, conn: &SyncConnection) {
+        let proxy = controller_proxy(conn, self.settings.timeout);
+
+        let polled = proxy
+            .method_call(CONTROLLER_INTERFACE, "CurrentInputMethod", ())
+            .and_then(|(name,): (String,)| {
+                let (state,): (i32,) = proxy.method_call(CONTROLLER_INTERFACE, "State", ())?;
+                Ok((name, state))
+            });
+
+        // fcitx5の再起動中は失敗するが、それは`NameOwnerChanged`から`BackendLost`として通知される
+        if let Ok(polled) = polled
+            && self.polled.as_ref() != Some(&polled)
+        {
+            self.polled = Some(polled);
+            let _ = self.sender.send(GetInputMethod);
+        }
+    }
+
+    fn remove(self, conn: &SyncConnection) -> Result<(), dbus::Error> {
+        let mut res = match self.new_icon_token {
+            Some(token) => conn.remove_match(token),
+            None => Ok(()),
+        };
+
+        for (token, (interface, member)) in self.native_tokens.into_iter().zip(NATIVE_SIGNALS) {
+            conn.stop_receive(token);
+            res = res.and(
+                conn.remove_match_no_cb(&MatchRule::new_signal(interface, member).match_str()),
+            );
+        }
+
+        res
+    }
+}
+
+fn process_while_running(
+    conn: &SyncConnection,
+    stop_handle: &StopHandle,
+    subscription: &mut Fcitx5Subscription,
+    resync: &AtomicBool,
+    filter: &ChangeFilter,
+) -> Result<(), dbus::Error> {
+    let mut last_poll = Instant::now();
+
+    while stop_handle.is_running() {
+        let mut timeout = filter.wait_timeout(subscription.settings.process_interval);
+        if subscription.polling {
+            timeout = timeout.min(POLL_INTERVAL.saturating_sub(last_poll.elapsed()));
+        }
+
+        conn.process(timeout)?;
+
+        if resync.swap(false, Ordering::SeqCst) {
+            for event in subscription.resync(conn)? {
+                filter.send(event);
+            }
+        }
+
+        if subscription.polling && last_poll.elapsed() >= POLL_INTERVAL {
+            subscription.poll(conn);
+            last_poll = Instant::now();
+        }
+
+        filter.tick();
+    }
+
+    Ok(())
+}
+
+impl ImeWatcher for Fcitx5Watcher {
+    type Error = ImeWatchError;
+
+    fn start(&mut self) -> Result<(), ImeWatchError> {
+        if self.stop_handle.is_running() {
+            return Ok(());
+        }
+
+        // 停止済みのスレッドが残っていれば回収する。前回の監視の失敗は`stop`を呼んだ側にのみ返し、
+        // 新しい監視の開始を妨げない
+        let _ = self.stop();
+
+        let conn = SyncConnection::new_session().map_err(ImeWatchError::NoSessionBus)?;
+
+        let (sender, receiver) = channel();
+
+        let resync = Arc::new(AtomicBool::new(false));
+        let backend_tokens = match_backend_changes(&conn, &resync)?;
+
+        let groups_changed = Arc::new(AtomicBool::new(false));
+        let mut subscription = Fcitx5Subscription::new(
+            &conn,
+            self.mode,
+            self.settings.clone(),
+            sender,
+            &groups_changed,
+        )?;
+
+        // 最初のシグナルを待たずに現在の状態を取得する
+        subscription.resync(&conn)?;
+
+        if subscription.backend.is_none() {
+            return Err(ImeWatchError::BackendNotRunning(
+                match self.mode {
+                    Fcitx5Mode::StatusNotifier => "StatusNotifierItem of fcitx5 is not found",
+                    Fcitx5Mode::Auto | Fcitx5Mode::Native => "fcitx5 is not running",
+                }
+                .to_owned(),
+            ));
+        }
+
+        let worker_conn = SyncConnection::new_session().map_err(ImeWatchError::NoSessionBus)?;
+
+        let filter = Arc::new(ChangeFilter::new(
+            self.broadcaster.clone(),
+            self.settings.emit,
+        ));
+
+        self.stop_handle.set_running();
+
+        let worker_thread = std::thread::spawn({
+            let filter = filter.clone();
+            let classifier = self.classifier.clone();
+            let stop_handle = self.stop_handle.clone();
+            let timeout = self.settings.timeout;
+            let debounce = self.settings.debounce;
+
+            move || {
+                let res = run_worker(
+                    worker_conn,
+                    receiver,
+                    &filter,
+                    &classifier,
+                    timeout,
+                    debounce,
+                    &groups_changed,
+                )
+                .map_err(ImeWatchError::from);
+
+                // ワーカーが失敗した場合は監視全体を止める
+                stop_handle.stop();
+                res
+            }
+        });
+
+        // 接続とともにsenderが全て破棄され、ワーカーも終了する。
+        let process_thread = std::thread::spawn({
+            let stop_handle = self.stop_handle.clone();
+            let broadcaster = self.broadcaster.clone();
+
+            move || {
+                let mut res =
+                    process_while_running(&conn, &stop_handle, &mut subscription, &resync, &filter)
+                        .and(subscription.remove(&conn));
+
+                for token in backend_tokens {
+                    res = res.and(conn.remove_match(token));
+                }
+
+                stop_handle.stop();
+                broadcaster.close();
+                res.map_err(ImeWatchError::from)
+            }
+        });
+
+        self.threads = vec![process_thread, worker_thread];
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), ImeWatchError> {
+        self.stop_handle.stop();
+
+        let mut res = Ok(());
+        for thread in self.threads.drain(..) {
+            let thread_res = thread.join().expect("fcitx5 watcher thread panicked");
+            if res.is_ok() {
+                res = thread_res;
+            }
+        }
+
+        res
+    }
+
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    fn current_state(&self) -> Option<ImeState> {
+        self.broadcaster.current_state()
+    }
+
+    fn subscribe(&self) -> Receiver<ImeEvent> {
+        self.broadcaster.subscribe()
+    }
+}
+
+impl Drop for Fcitx5Watcher {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_changes_after_the_first_group() {
+        let mut groups = GroupTracker::default();
+
+        assert_eq!(groups.update("Default".to_owned(), false), None);
+        assert_eq!(groups.update("Default".to_owned(), false), None);
+        assert_eq!(
+            groups.update("Japanese".to_owned(), false),
+            Some(ImeEvent::GroupChanged("Japanese".to_owned()))
+        );
+        // 内容の変更は同じ名前で通知される
+        assert_eq!(
+            groups.update("Japanese".to_owned(), true),
+            Some(ImeEvent::GroupChanged("Japanese".to_owned()))
+        );
+    }
+
+    #[test]
+    fn no_input_context_has_no_open_state() {
+        assert_eq!(open_state(0), None);
+        assert_eq!(open_state(1), Some(false));
+        assert_eq!(open_state(2), Some(true));
+        assert_eq!(open_state(3), None);
+    }
+
+    #[test]
+    fn polls_only_without_signals() {
+        assert!(!needs_polling(Fcitx5Mode::Auto, true, false));
+        assert!(!needs_polling(Fcitx5Mode::Auto, false, true));
+        assert!(needs_polling(Fcitx5Mode::Auto, false, false));
+
+        // トレイがあってもkimpanelのパネルがなければ切り替えは通知されない
+        assert!(!needs_polling(Fcitx5Mode::Native, true, false));
+        assert!(needs_polling(Fcitx5Mode::Native, false, true));
+
+        assert!(!needs_polling(Fcitx5Mode::StatusNotifier, false, false));
+    }
+
+    #[test]
+    fn backend_transitions() {
+        assert_eq!(backend_transition(None, None), []);
+        assert_eq!(backend_transition(Some(":1.10"), Some(":1.10")), []);
+        assert_eq!(
+            backend_transition(None, Some(":1.10")),
+            [ImeEvent::BackendRecovered]
+        );
+        assert_eq!(
+            backend_transition(Some(":1.10"), None),
+            [ImeEvent::BackendLost]
+        );
+        assert_eq!(
+            backend_transition(Some(":1.10"), Some(":1.42")),
+            [ImeEvent::BackendLost, ImeEvent::BackendRecovered]
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! フォーカスされているアプリケーションの取得と、[`AppMemory`]の実行。
+//!
+//! fcitx5ではフォーカスされた入力コンテキストのプログラム名を`DebugInfo`から読む。
+//! 取得できない場合とIBusでは、X11の`_NET_ACTIVE_WINDOW`の`WM_CLASS`を読む。
+//! X11への接続は最初に必要になったときに作り、使い回す。
+
+use std::sync::mpsc::Receiver;
+
+use x11rb::connection::Connection;
+use x11rb::errors::{ConnectError, ReplyError};
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt, Window};
+use x11rb::rust_connection::RustConnection;
+
+use super::fcitx5::Fcitx5Controller;
+use super::ibus::IbusController;
+use super::{Backend, DbusSettings, ImeWatchError};
+use crate::ImeEvent;
+use crate::app_memory::AppMemory;
+
+/// フォーカスの取得に失敗した理由
+#[derive(Debug)]
+pub enum FocusError {
+    /// fcitx5への問い合わせに失敗した
+    Fcitx5(ImeWatchError),
+    /// X11のディスプレイがない。IBusではフォーカスを調べる方法がない
+    NoX11Display,
+    /// X11のディスプレイに接続できない
+    X11Connect(ConnectError),
+    /// X11のプロパティを取得できない
+    X11(ReplyError),
+}
+
+impl std::fmt::Display for FocusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FocusError::Fcitx5(e) => write!(f, "failed to get the focused program: {e}"),
+            FocusError::NoX11Display => {
+                write!(f, "DISPLAY is not set, the focused window cannot be read")
+            }
+            FocusError::X11Connect(e) => write!(f, "failed to connect to the X11 display: {e}"),
+            FocusError::X11(e) => write!(f, "failed to get the active X11 window: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FocusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FocusError::Fcitx5(e) => Some(e),
+            FocusError::X11Connect(e) => Some(e),
+            FocusError::X11(e) => Some(e),
+            FocusError::NoX11Display => None,
+        }
+    }
+}
+
+/// `DebugInfo`のうち、フォーカスされた入力コンテキストのプログラム名
+///
+/// 入力コンテキストごとに`IC [...] program:firefox frontend:dbus cap:... focus:1`の行がある。
+pub fn parse_debug_info(debug_info: &str) -> Option<String> {
+    debug_info
+        .lines()
+        .filter(|line| line.split_whitespace().any(|field| field == "focus:1"))
+        .find_map(|line| {
+            line.split_whitespace()
+                .find_map(|field| field.strip_prefix("program:"))
+                .filter(|program| !program.is_empty())
+        })
+        .map(str::to_owned)
+}
+
+/// `WM_CLASS`の値のクラス(`Navigator\0firefox\0`の`firefox`)
+pub fn parse_wm_class(value: &[u8]) -> Option<String> {
+    value
+        .split(|&byte| byte == 0)
+        .nth(1)
+        .filter(|class| !class.is_empty())
+        .map(|class| String::from_utf8_lossy(class).into_owned())
+}
+
+/// X11のディスプレイへの接続と、ルートウィンドウの`_NET_ACTIVE_WINDOW`
+struct X11Display {
+    conn: RustConnection,
+    root: Window,
+    net_active_window: Atom,
+}
+
+impl X11Display {
+    fn connect() -> Result<Self, FocusError> {
+        let (conn, screen) = x11rb::connect(None).map_err(FocusError::X11Connect)?;
+        let root = conn.setup().roots[screen].root;
+
+        let net_active_window = conn
+            .intern_atom(false, b"_NET_ACTIVE_WINDOW")
+            .map_err(ReplyError::from)
+            .and_then(|cookie| cookie.reply())
+            .map_err(FocusError::X11)?
+            .atom;
+
+        Ok(Self {
+            conn,
+            root,
+            net_active_window,
+        })
+    }
+
+    fn active_window(&self) -> Result<Option<Window>, ReplyError> {
+        let reply = self
+            .conn
+            .get_property(
+                false,
+                self.root,
+                self.net_active_window,
+                AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()?;
+
+        Ok(reply
+            .value32()
+            .and_then(|mut windows| windows.next())
+            .filter(|&window| window != 0))
+    }
+
+    fn focused_class(&self) -> Result<Option<String>, ReplyError> {
+        let Some(window) = self.active_window()? else {
+            return Ok(None);
+        };
+
+        // クラス名に十分な長さ(32ビット単位)
+        let reply = self
+            .conn
+            .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 256)?
+            .reply()?;
+
+        Ok(parse_wm_class(&reply.value))
+    }
+}
+
+/// X11でアクティブなウィンドウの`WM_CLASS`のクラスを調べる。
+#[derive(Default)]
+pub struct X11Focus {
+    display: Option<X11Display>,
+}
+
+impl X11Focus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// アクティブなウィンドウのクラス。失敗した場合は次回に接続し直す
+    pub fn focused_class(&mut self) -> Result<Option<String>, FocusError> {
+        let display = match &mut self.display {
+            Some(display) => display,
+            None => self.display.insert(X11Display::connect()?),
+        };
+
+        display.focused_class().map_err(|e| {
+            self.display = None;
+            FocusError::X11(e)
+        })
+    }
+}
+
+/// X11のディスプレイがあるか
+fn has_x11_display() -> bool {
+    std::env::var_os("DISPLAY").is_some_and(|display| !display.is_empty())
+}
+
+/// fcitx5のプログラム名、取得できなければX11のクラス
+fn fcitx5_focused_app(
+    controller: &Fcitx5Controller,
+    x11: &mut X11Focus,
+) -> Result<Option<String>, FocusError> {
+    match controller.focused_program().map_err(FocusError::Fcitx5)? {
+        Some(program) => Ok(Some(program)),
+        None if has_x11_display() => x11.focused_class(),
+        None => Ok(None),
+    }
+}
+
+/// IBusのエンジンを切り替える。ibus-daemonの再起動で接続が切れていれば接続し直す
+fn switch_ibus_engine(
+    controller: &mut IbusController,
+    settings: &DbusSettings,
+    engine: &str,
+) -> Result<(), ImeWatchError> {
+    match controller.set_engine(engine) {
+        Err(ImeWatchError::Disconnected(_)) => {
+            *controller = IbusController::connect(settings)?;
+            controller.set_engine(engine)
+        }
+        res => res,
+    }
+}
+
+/// `events`が切断されるまで、`backend`でフォーカスを追ってエンジンを切り替える。
+///
+/// 接続できない場合はすぐに失敗する。その後の失敗は`report`に渡して続ける。
+/// IBusでX11のディスプレイがない場合は、それを`report`に渡して何もせずに戻る。
+pub fn run_app_memory(
+    backend: Backend,
+    settings: &DbusSettings,
+    memory: AppMemory,
+    events: &Receiver<ImeEvent>,
+    mut report: impl FnMut(&dyn std::error::Error),
+) -> Result<(), ImeWatchError> {
+    match backend {
+        Backend::Fcitx5 => {
+            let controller = Fcitx5Controller::connect(settings)?;
+            let mut x11 = X11Focus::new();
+
+            memory.run(
+                events,
+                || fcitx5_focused_app(&controller, &mut x11),
+                |engine| controller.set_current_im(engine),
+                report,
+            );
+        }
+        Backend::Ibus => {
+            if !has_x11_display() {
+                report(&FocusError::NoX11Display);
+                return Ok(());
+            }
+
+            let mut controller = IbusController::connect(settings)?;
+            let mut x11 = X11Focus::new();
+
+            memory.run(
+                events,
+                || x11.focused_class(),
+                |engine| switch_ibus_engine(&mut controller, settings, engine),
+                report,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_focused_program_from_debug_info() {
+        let debug_info = "\
+Group [x11::0] has 2 InputContext(s)
+  IC [6a1c0e5d6c1a4b2f9d8e7f6a5b4c3d2e] program:kitty frontend:xim cap:0 focus:0
+  IC [0f1e2d3c4b5a69788796a5b4c3d2e1f0] program:org.telegram.desktop frontend:dbus cap:4000012 focus:1
+Group [wayland:] has 1 InputContext(s)
+  IC [11223344556677889900aabbccddeeff] program: frontend:wayland cap:0 focus:0
+Input Context without group
+";
+
+        assert_eq!(
+            parse_debug_info(debug_info).as_deref(),
+            Some("org.telegram.desktop")
+        );
+        assert_eq!(
+            parse_debug_info(&debug_info.replace("focus:1", "focus:0")),
+            None
+        );
+        // プログラム名のない入力コンテキストはフォーカスされていても分からない
+        assert_eq!(
+            parse_debug_info("  IC [00] program: frontend:wayland cap:0 focus:1"),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_class_from_wm_class() {
+        assert_eq!(
+            parse_wm_class(b"Navigator\0firefox\0").as_deref(),
+            Some("firefox")
+        );
+        assert_eq!(parse_wm_class(b"kitty\0").as_deref(), None);
+        assert_eq!(parse_wm_class(b""), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! `ibus address`コマンドを使わずにIBusのバスのアドレスを求める。
+//!
+//! ibus-daemonは`$XDG_CONFIG_HOME/ibus/bus/<machine-id>-<host>-<display>`に
+//! `IBUS_ADDRESS`と`IBUS_DAEMON_PID`を書き出すため、これを読む。
+
+use std::path::PathBuf;
+
+/// アドレスの解決に失敗した理由
+#[derive(Debug)]
+pub enum AddressError {
+    /// 設定ディレクトリ(`$XDG_CONFIG_HOME`, `$HOME`)が分からない
+    NoConfigDir,
+    /// マシンIDが読めない
+    NoMachineId,
+    /// アドレスファイルが読めない
+    Io(PathBuf, std::io::Error),
+    /// アドレスファイルに`IBUS_ADDRESS`がない
+    MissingAddress(PathBuf),
+    /// `IBUS_DAEMON_PID`のプロセスが存在しない(古いファイル)
+    DaemonNotRunning(PathBuf),
+}
+
+impl std::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressError::NoConfigDir => {
+                write!(f, "neither XDG_CONFIG_HOME nor HOME is set")
+            }
+            AddressError::NoMachineId => write!(f, "failed to read the machine id"),
+            AddressError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            AddressError::MissingAddress(path) => {
+                write!(f, "IBUS_ADDRESS is not found in {}", path.display())
+            }
+            AddressError::DaemonNotRunning(path) => {
+                write!(f, "ibus-daemon of {} is not running", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AddressError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// アドレスの解決に用いる環境。テストでは各パスを一時ディレクトリに差し替える。
+#[derive(Debug, Clone, Default)]
+pub struct AddressLookup {
+    /// `IBUS_ADDRESS`
+    pub ibus_address: Option<String>,
+    /// `IBUS_ADDRESS_FILE`。指定された場合はファイル名の組み立てを省く
+    pub address_file: Option<PathBuf>,
+    /// `$XDG_CONFIG_HOME`または`~/.config`
+    pub config_dir: Option<PathBuf>,
+    /// 先頭から順に読むマシンIDのファイル
+    pub machine_id_files: Vec<PathBuf>,
+    /// `DISPLAY`
+    pub display: Option<String>,
+    /// `WAYLAND_DISPLAY`
+    pub wayland_display: Option<String>,
+    /// `IBUS_DAEMON_PID`の確認に用いる`/proc`
+    pub proc_dir: PathBuf,
+}
+
+fn non_empty_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+impl AddressLookup {
+    /// 環境変数と実際のファイルシステムから作る。
+    pub fn from_env() -> Self {
+        Self {
+            ibus_address: non_empty_var("IBUS_ADDRESS"),
+            address_file: non_empty_var("IBUS_ADDRESS_FILE").map(PathBuf::from),
+            config_dir: crate::config::config_dir(),
+            machine_id_files: vec![
+                PathBuf::from("/etc/machine-id"),
+                PathBuf::from("/var/lib/dbus/machine-id"),
+            ],
+            display: non_empty_var("DISPLAY"),
+            wayland_display: non_empty_var("WAYLAND_DISPLAY"),
+            proc_dir: PathBuf::from("/proc"),
+        }
+    }
+
+    fn machine_id(&self) -> Result<String, AddressError> {
+        self.machine_id_files
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .map(|id| id.trim().to_string())
+            .find(|id| !id.is_empty())
+            .ok_or(AddressError::NoMachineId)
+    }
+
+    /// ファイル名の`<host>-<display>`の部分。ibusと同じくWaylandを優先する。
+    fn display_part(&self) -> String {
+        if let Some(wayland_display) = &self.wayland_display {
+            return format!("unix-{wayland_display}");
+        }
+
+        // `host:number.screen`の形式
+        let display = self.display.as_deref().unwrap_or(":0.0");
+        let (host, rest) = display.split_once(':').unwrap_or(("", display));
+        let number = rest
+            .split_once('.')
+            .map_or(rest, |(number, _screen)| number);
+
+        let host = if host.is_empty() { "unix" } else { host };
+
+        format!("{host}-{number}")
+    }
+
+    /// 読むべきアドレスファイルのパス
+    pub fn address_file_path(&self) -> Result<PathBuf, AddressError> {
+        if let Some(path) = &self.address_file {
+            return Ok(path.clone());
+        }
+
+        let config_dir = self.config_dir.as_ref().ok_or(AddressError::NoConfigDir)?;
+        let file_name = format!("{}-{}", self.machine_id()?, self.display_part());
+
+        Ok(config_dir.join("ibus").join("bus").join(file_name))
+    }
+
+    /// `IBUS_ADDRESS`、なければアドレスファイルからアドレスを求める。
+    pub fn resolve(&self) -> Result<String, AddressError> {
+        if let Some(address) = &self.ibus_address {
+            return Ok(address.clone());
+        }
+
+        let path = self.address_file_path()?;
+        let content =
+            std::fs::read_to_string(&path).map_err(|e| AddressError::Io(path.clone(), e))?;
+
+        let mut address = None;
+        let mut pid = None;
+
+        for line in content.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some(("IBUS_ADDRESS", value)) => address = Some(value.trim().to_string()),
+                Some(("IBUS_DAEMON_PID", value)) => pid = value.trim().parse::<u32>().ok(),
+                _ => {}
+            }
+        }
+
+        let address = address
+            .filter(|address| !address.is_empty())
+            .ok_or_else(|| AddressError::MissingAddress(path.clone()))?;
+
+        // ibus-daemonの終了後もファイルは残るため、プロセスの存在を確かめる
+        match pid {
+            Some(pid) if self.proc_dir.join(pid.to_string()).exists() => Ok(address),
+            _ => Err(AddressError::DaemonNotRunning(path)),
+        }
+    }
+}
+
+/// 現在の環境でのIBusのバスのアドレス
+pub fn ibus_address() -> Result<String, AddressError> {
+    AddressLookup::from_env().resolve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE_ID: &str = "0123456789abcdef";
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(dir.path().join("config/ibus/bus")).unwrap();
+            std::fs::create_dir_all(dir.path().join("proc/4242")).unwrap();
+            std::fs::write(dir.path().join("machine-id"), format!("{MACHINE_ID}\n")).unwrap();
+
+            Self { dir }
+        }
+
+        fn lookup(&self) -> AddressLookup {
+            AddressLookup {
+                config_dir: Some(self.dir.path().join("config")),
+                machine_id_files: vec![
+                    self.dir.path().join("missing-machine-id"),
+                    self.dir.path().join("machine-id"),
+                ],
+                proc_dir: self.dir.path().join("proc"),
+                ..Default::default()
+            }
+        }
+
+        fn write_bus_file(&self, name: &str, pid: u32) {
+            let content = format!(
+                "# This file is created by ibus-daemon, please do not modify it.\n\
+                 IBUS_ADDRESS=unix:path=/tmp/ibus-test,guid=0123\n\
+                 IBUS_DAEMON_PID={pid}\n"
+            );
+            std::fs::write(self.dir.path().join("config/ibus/bus").join(name), content).unwrap();
+        }
+    }
+
+    #[test]
+    fn env_address_takes_precedence() {
+        let fixture = Fixture::new();
+        let lookup = AddressLookup {
+            ibus_address: Some("unix:path=/tmp/from-env".to_string()),
+            ..fixture.lookup()
+        };
+
+        assert_eq!(lookup.resolve().unwrap(), "unix:path=/tmp/from-env");
+    }
+
+    #[test]
+    fn reads_x11_display_file() {
+        let fixture = Fixture::new();
+        fixture.write_bus_file(&format!("{MACHINE_ID}-unix-1"), 4242);
+
+        let lookup = AddressLookup {
+            display: Some(":1.0".to_string()),
+            ..fixture.lookup()
+        };
+
+        assert_eq!(
+            lookup.resolve().unwrap(),
+            "unix:path=/tmp/ibus-test,guid=0123"
+        );
+    }
+
+    #[test]
+    fn display_defaults_and_remote_host() {
+        let fixture = Fixture::new();
+
+        let file_name = |lookup: AddressLookup| {
+            let path = lookup.address_file_path().unwrap();
+            path.file_name().unwrap().to_string_lossy().into_owned()
+        };
+
+        assert_eq!(file_name(fixture.lookup()), format!("{MACHINE_ID}-unix-0"));
+        assert_eq!(
+            file_name(AddressLookup {
+                display: Some("remote:10.0".to_string()),
+                ..fixture.lookup()
+            }),
+            format!("{MACHINE_ID}-remote-10")
+        );
+        assert_eq!(
+            file_name(AddressLookup {
+                display: Some(":0".to_string()),
+                wayland_display: Some("wayland-0".to_string()),
+                ..fixture.lookup()
+            }),
+            format!("{MACHINE_ID}-unix-wayland-0")
+        );
+    }
+
+    #[test]
+    fn stale_pid_is_rejected() {
+        let fixture = Fixture::new();
+        fixture.write_bus_file(&format!("{MACHINE_ID}-unix-0"), 999_999);
+
+        assert!(matches!(
+            fixture.lookup().resolve(),
+            Err(AddressError::DaemonNotRunning(_))
+        ));
+    }
+
+    #[test]
+    fn missing_file_is_io_error() {
+        let fixture = Fixture::new();
+
+        assert!(matches!(
+            fixture.lookup().resolve(),
+            Err(AddressError::Io(..))
+        ));
+    }
+}
@@ -0,0 +1,268 @@
+//! `SetGlobalEngine`による切り替え。
+//!
+//! IBusには直接入力への切り替えがないため、種類ごとに最後に使ったエンジンを覚えておき、
+//! その間で切り替える。CLIのように毎回接続し直す場合は[`EngineHistory`]をファイルに保存する。
+
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use serde::Deserialize;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{EngineDesc, connect, engine_descs, global_engine, ibus_proxy};
+use crate::config::toml_string;
+use crate::linux::{DbusSettings, ImeWatchError};
+use crate::{EngineClassifier, ImeState, InputKind};
+
+/// 種類ごとに最後に使ったエンジンの名前
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct EngineHistory {
+    pub direct: Option<String>,
+    pub composing: Option<String>,
+}
+
+impl EngineHistory {
+    /// `$XDG_RUNTIME_DIR/ime-watcher-ibus-history.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+
+        Some(PathBuf::from(runtime_dir).join("ime-watcher-ibus-history.toml"))
+    }
+
+    /// 保存した履歴を読み込む。ファイルがない場合や壊れている場合は空とする
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|toml| toml::from_str(&toml).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut toml = String::new();
+
+        for (key, engine) in [("direct", &self.direct), ("composing", &self.composing)] {
+            if let Some(engine) = engine {
+                toml.push_str(&format!("{key} = {}\n", toml_string(engine)));
+            }
+        }
+
+        std::fs::write(path, toml)
+    }
+
+    /// 種類が分からないエンジンは覚えない。
+    pub fn record(&mut self, state: &ImeState) {
+        match state.kind {
+            InputKind::Direct => self.direct = Some(state.engine.clone()),
+            InputKind::Composing => self.composing = Some(state.engine.clone()),
+            InputKind::Unknown => {}
+        }
+    }
+
+    pub fn last(&self, kind: InputKind) -> Option<&str> {
+        match kind {
+            InputKind::Direct => self.direct.as_deref(),
+            InputKind::Composing => self.composing.as_deref(),
+            InputKind::Unknown => None,
+        }
+    }
+}
+
+/// `org.freedesktop.IBus`でグローバルエンジンを切り替える。
+pub struct IbusController {
+    conn: Connection,
+    timeout: Duration,
+    classifier: EngineClassifier,
+    history: EngineHistory,
+}
+
+impl IbusController {
+    /// IBusのバスに接続する。ibus-daemonが起動していない場合は失敗する
+    pub fn connect(settings: &DbusSettings) -> Result<Self, ImeWatchError> {
+        Ok(Self {
+            conn: connect()?,
+            timeout: settings.timeout,
+            classifier: EngineClassifier::default(),
+            history: EngineHistory::default(),
+        })
+    }
+
+    /// エンジンの種類の判定に用いる。
+    pub fn with_classifier(mut self, classifier: EngineClassifier) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    /// 以前に保存した履歴から始める。
+    pub fn with_history(mut self, history: EngineHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// 切り替えのたびに更新される履歴
+    pub fn history(&self) -> &EngineHistory {
+        &self.history
+    }
+
+    /// インストールされているエンジンの一覧。読み取れないエンジンは飛ばす
+    pub fn list_engines(&self) -> Result<Vec<EngineDesc>, ImeWatchError> {
+        engine_descs(&ibus_proxy(&self.conn, self.timeout))
+    }
+
+    /// `ListActiveEngines`で得られる、ユーザーが有効にしているエンジン。読み取れないエンジンは飛ばす
+    pub fn active_engines(&self) -> Result<Vec<EngineDesc>, ImeWatchError> {
+        let (engines,): (Vec<Variant<Box<dyn RefArg>>>,) = ibus_proxy(&self.conn, self.timeout)
+            .method_call("org.freedesktop.IBus", "ListActiveEngines", ())?;
+
+        Ok(engines
+            .iter()
+            .filter_map(|engine| EngineDesc::from_ref_arg(engine).ok())
+            .collect())
+    }
+
+    /// 現在のグローバルエンジン。まだ設定されていない場合は`None`
+    pub fn global_engine(&self) -> Result<Option<EngineDesc>, ImeWatchError> {
+        global_engine(&self.conn, self.timeout)
+    }
+
+    /// 現在のエンジンの状態
+    pub fn current_state(&self) -> Result<Option<ImeState>, ImeWatchError> {
+        Ok(self
+            .global_engine()?
+            .map(|desc| desc.to_state(&self.classifier)))
+    }
+
+    /// エンジンを切り替える。インストールされていない名前は`UnknownEngine`
+    ///
+    /// ibus-daemonの`Failed`では理由が分からないため、先に一覧と照合する。
+    pub fn set_engine(&mut self, name: &str) -> Result<(), ImeWatchError> {
+        let desc = self
+            .list_engines()?
+            .into_iter()
+            .find(|desc| desc.name == name)
+            .ok_or_else(|| ImeWatchError::UnknownEngine(name.to_owned()))?;
+
+        // 切り替え前のエンジンも戻り先として覚えておく
+        if let Some(current) = self.current_state()? {
+            self.history.record(&current);
+        }
+
+        ibus_proxy(&self.conn, self.timeout).method_call::<(), _, _, _>(
+            "org.freedesktop.IBus",
+            "SetGlobalEngine",
+            (name,),
+        )?;
+
+        self.history.record(&desc.to_state(&self.classifier));
+
+        Ok(())
+    }
+
+    /// 最後に使った`kind`のエンジンに切り替える。既にその種類なら何もしない
+    ///
+    /// まだ使っていなければ、有効にされているエンジンのうち最初の`kind`のエンジンに切り替える。
+    pub fn switch_to(&mut self, kind: InputKind) -> Result<(), ImeWatchError> {
+        let current = self.current_state()?;
+
+        if let Some(current) = &current {
+            self.history.record(current);
+
+            if current.kind == kind {
+                return Ok(());
+            }
+        }
+
+        let engine = match self.history.last(kind) {
+            Some(engine) => engine.to_owned(),
+            None => first_of_kind(&self.active_engines()?, kind, &self.classifier)
+                .ok_or(ImeWatchError::NoEngineToSwitch(kind))?,
+        };
+
+        self.set_engine(&engine)
+    }
+
+    /// 変換を伴うエンジンなら直接入力に、それ以外なら変換を伴うエンジンに切り替える。
+    pub fn toggle(&mut self) -> Result<(), ImeWatchError> {
+        let kind = match self.current_state()? {
+            Some(state) if state.kind == InputKind::Composing => InputKind::Direct,
+            _ => InputKind::Composing,
+        };
+
+        self.switch_to(kind)
+    }
+}
+
+/// `descs`のうち最初の`kind`のエンジンの名前
+fn first_of_kind(
+    descs: &[EngineDesc],
+    kind: InputKind,
+    classifier: &EngineClassifier,
+) -> Option<String> {
+    descs
+        .iter()
+        .find(|desc| desc.to_state(classifier).kind == kind)
+        .map(|desc| desc.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_remembers_last_engine_of_each_kind() {
+        let classifier = EngineClassifier::builtin();
+        let mut history = EngineHistory::default();
+
+        history.record(&classifier.state("xkb:us::eng"));
+        history.record(&classifier.state("anthy"));
+        history.record(&classifier.state("mozc-jp"));
+        history.record(&ImeState::new("unknown-engine"));
+
+        assert_eq!(history.last(InputKind::Direct), Some("xkb:us::eng"));
+        assert_eq!(history.last(InputKind::Composing), Some("mozc-jp"));
+        assert_eq!(history.last(InputKind::Unknown), None);
+    }
+
+    #[test]
+    fn history_round_trips_through_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.toml");
+
+        // 壊れたファイルや存在しないファイルは空の履歴とする
+        assert_eq!(EngineHistory::load(&path), EngineHistory::default());
+        std::fs::write(&path, "direct = ").unwrap();
+        assert_eq!(EngineHistory::load(&path), EngineHistory::default());
+
+        let history = EngineHistory {
+            direct: Some("xkb:us::eng".to_owned()),
+            composing: Some("odd\"name".to_owned()),
+        };
+        history.save(&path).unwrap();
+
+        assert_eq!(EngineHistory::load(&path), history);
+    }
+
+    #[test]
+    fn falls_back_to_first_active_engine_of_kind() {
+        let classifier = EngineClassifier::builtin();
+        let desc = |name: &str| EngineDesc {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        let active = [desc("unknown-engine"), desc("xkb:us::eng"), desc("mozc-jp")];
+
+        assert_eq!(
+            first_of_kind(&active, InputKind::Composing, &classifier).as_deref(),
+            Some("mozc-jp")
+        );
+        assert_eq!(
+            first_of_kind(&active, InputKind::Direct, &classifier).as_deref(),
+            Some("xkb:us::eng")
+        );
+        assert_eq!(
+            first_of_kind(&active[..2], InputKind::Composing, &classifier),
+            None
+        );
+    }
+}
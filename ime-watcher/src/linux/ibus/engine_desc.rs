@@ -0,0 +1,366 @@
+//! IBusがシリアライズした`IBusEngineDesc`の読み取り。
+//!
+//! `(sa{sv}ssssssssussssssss)`の形式で、先頭は型名と添付情報。
+//! 古いIBusでは`rank`より後のフィールドが欠けているため、その場合は空文字列とする。
+
+use dbus::arg::{ArgType, RefArg};
+
+use std::collections::HashMap;
+
+use crate::{EngineClassifier, ImeState};
+
+const TYPE_NAME: &str = "IBusEngineDesc";
+
+/// `IBusEngineDesc`の読み取りに失敗した理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineDescError {
+    /// 構造体ではない
+    NotStruct(ArgType),
+    /// 型名が`IBusEngineDesc`ではない
+    UnexpectedType(String),
+    /// 必須のフィールドがない
+    MissingField(&'static str),
+    /// フィールドの型が異なる
+    InvalidField(&'static str),
+}
+
+impl std::fmt::Display for EngineDescError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineDescError::NotStruct(arg_type) => {
+                write!(f, "IBusEngineDesc must be a struct, found {arg_type:?}")
+            }
+            EngineDescError::UnexpectedType(type_name) => {
+                write!(f, "expected {TYPE_NAME}, found {type_name}")
+            }
+            EngineDescError::MissingField(field) => write!(f, "missing field `{field}`"),
+            EngineDescError::InvalidField(field) => write!(f, "invalid type of field `{field}`"),
+        }
+    }
+}
+
+impl std::error::Error for EngineDescError {}
+
+/// IBusのエンジンの情報
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EngineDesc {
+    pub name: String,
+    pub longname: String,
+    pub description: String,
+    /// `ja`, `en`などの言語
+    pub language: String,
+    pub license: String,
+    pub author: String,
+    pub icon: String,
+    pub layout: String,
+    /// 大きいほど優先される
+    pub rank: u32,
+    pub hotkeys: String,
+    /// パネルに表示する記号(`あ`, `en`など)
+    pub symbol: String,
+    pub setup: String,
+    pub layout_variant: String,
+    pub layout_option: String,
+    pub version: String,
+    pub textdomain: String,
+    pub icon_prop_key: String,
+}
+
+/// `Variant`で包まれている場合は中身を取り出す。
+fn unwrap_variant(mut value: &dyn RefArg) -> &dyn RefArg {
+    while value.arg_type() == ArgType::Variant {
+        match value.as_iter().and_then(|mut iter| iter.next()) {
+            Some(inner) => value = inner,
+            None => break,
+        }
+    }
+
+    value
+}
+
+impl EngineDesc {
+    /// D-Busの値から読み取る。`Variant`で包まれていてもよい。
+    pub fn from_ref_arg(value: &dyn RefArg) -> Result<Self, EngineDescError> {
+        let value = unwrap_variant(value);
+
+        if value.arg_type() != ArgType::Struct {
+            return Err(EngineDescError::NotStruct(value.arg_type()));
+        }
+
+        let fields: Vec<&dyn RefArg> = value
+            .as_iter()
+            .ok_or(EngineDescError::NotStruct(value.arg_type()))?
+            .collect();
+
+        let type_name = fields
+            .first()
+            .and_then(|field| field.as_str())
+            .ok_or(EngineDescError::MissingField("type name"))?;
+        if type_name != TYPE_NAME {
+            return Err(EngineDescError::UnexpectedType(type_name.to_owned()));
+        }
+
+        // 0: 型名, 1: 添付情報
+        let string = |index: usize, field: &'static str| -> Result<String, EngineDescError> {
+            let value = fields
+                .get(index)
+                .ok_or(EngineDescError::MissingField(field))?;
+            value
+                .as_str()
+                .map(str::to_owned)
+                .ok_or(EngineDescError::InvalidField(field))
+        };
+        let optional_string = |index: usize, field: &'static str| match fields.get(index) {
+            Some(_) => string(index, field),
+            None => Ok(String::new()),
+        };
+
+        let rank = fields
+            .get(10)
+            .ok_or(EngineDescError::MissingField("rank"))?;
+        if rank.arg_type() != ArgType::UInt32 {
+            return Err(EngineDescError::InvalidField("rank"));
+        }
+
+        Ok(Self {
+            name: string(2, "name")?,
+            longname: string(3, "longname")?,
+            description: string(4, "description")?,
+            language: string(5, "language")?,
+            license: string(6, "license")?,
+            author: string(7, "author")?,
+            icon: string(8, "icon")?,
+            layout: string(9, "layout")?,
+            rank: rank.as_u64().ok_or(EngineDescError::InvalidField("rank"))? as u32,
+            hotkeys: optional_string(11, "hotkeys")?,
+            symbol: optional_string(12, "symbol")?,
+            setup: optional_string(13, "setup")?,
+            layout_variant: optional_string(14, "layout_variant")?,
+            layout_option: optional_string(15, "layout_option")?,
+            version: optional_string(16, "version")?,
+            textdomain: optional_string(17, "textdomain")?,
+            icon_prop_key: optional_string(18, "icon_prop_key")?,
+        })
+    }
+
+    /// 判定結果にエンジン自身の表示名・言語・記号を加えた状態
+    pub fn to_state(&self, classifier: &EngineClassifier) -> ImeState {
+        let mut state = classifier.state(&self.name);
+
+        if !self.longname.is_empty() {
+            state = state.with_display_name(&self.longname);
+        }
+        // レイアウト名からの判定の方が詳しいため、判定できなかった場合のみ用いる
+        if state.language.is_none() && !self.language.is_empty() {
+            state = state.with_language(&self.language);
+        }
+        if !self.symbol.is_empty() {
+            state = state.with_symbol(&self.symbol);
+        }
+
+        state
+    }
+}
+
+/// エンジン名から`IBusEngineDesc`を引く。
+///
+/// `GlobalEngineChanged`はエンジン名しか送らないため、切り替え後の状態にも表示名・言語・記号を
+/// 加えるのに用いる。
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EngineDescCache {
+    descs: HashMap<String, EngineDesc>,
+}
+
+impl EngineDescCache {
+    pub(crate) fn new(descs: impl IntoIterator<Item = EngineDesc>) -> Self {
+        Self {
+            descs: descs
+                .into_iter()
+                .map(|desc| (desc.name.clone(), desc))
+                .collect(),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.descs.contains_key(name)
+    }
+
+    /// `name`のエンジンの状態
+    ///
+    /// 一覧にないエンジンは`fetch`で取得して覚える。取得できなければ判定結果のみとする。
+    pub(crate) fn state(
+        &mut self,
+        name: String,
+        classifier: &EngineClassifier,
+        fetch: impl FnOnce() -> Option<EngineDesc>,
+    ) -> ImeState {
+        if let Some(desc) = self.descs.get(&name) {
+            return desc.to_state(classifier);
+        }
+
+        match fetch().filter(|desc| desc.name == name) {
+            Some(desc) => {
+                let state = desc.to_state(classifier);
+                self.descs.insert(name, desc);
+                state
+            }
+            None => classifier.state(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputKind;
+
+    use dbus::Message;
+    use dbus::arg::Variant;
+    use std::collections::VecDeque;
+
+    /// `Properties.Get("org.freedesktop.IBus", "GlobalEngine")`の応答
+    fn read_global_engine(bytes: &[u8]) -> Result<EngineDesc, EngineDescError> {
+        let message = Message::demarshal(bytes).unwrap();
+        let value: Variant<Box<dyn RefArg>> = message.read1().unwrap();
+
+        EngineDesc::from_ref_arg(&value)
+    }
+
+    #[test]
+    fn decodes_mozc() {
+        let desc =
+            read_global_engine(include_bytes!("testdata/global_engine_mozc_jp.bin")).unwrap();
+
+        assert_eq!(
+            desc,
+            EngineDesc {
+                name: "mozc-jp".into(),
+                longname: "Mozc".into(),
+                description: "Mozc (Japanese Input Method)".into(),
+                language: "ja".into(),
+                license: "New BSD".into(),
+                author: "Google Inc.".into(),
+                icon: "/usr/share/ibus-mozc/icons/product_icon.png".into(),
+                layout: "default".into(),
+                rank: 80,
+                hotkeys: "".into(),
+                symbol: "あ".into(),
+                setup: "/usr/lib/mozc/mozc_tool --mode=config_dialog".into(),
+                layout_variant: "".into(),
+                layout_option: "".into(),
+                version: "2.29.5160.102".into(),
+                textdomain: "ibus-mozc".into(),
+                icon_prop_key: "InputMode".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn state_from_desc() {
+        let classifier = EngineClassifier::builtin();
+
+        let desc = read_global_engine(include_bytes!("testdata/global_engine_xkb_us.bin")).unwrap();
+        assert_eq!(desc.rank, 99);
+        assert_eq!(desc.textdomain, "ibus10");
+
+        let state = desc.to_state(&classifier);
+        assert_eq!(state.kind, InputKind::Direct);
+        assert_eq!(state.language.as_deref(), Some("en"));
+        assert_eq!(state.display_name.as_deref(), Some("English (US)"));
+        assert_eq!(state.symbol.as_deref(), Some("en"));
+
+        // 判定表で言語が分からないエンジンはエンジン自身の言語を用いる
+        let desc = EngineDesc::from_ref_arg(&build(
+            TYPE_NAME,
+            &["my-engine", "My Engine", "", "ko", "", "", "", "us"],
+            Some(0),
+        ))
+        .unwrap();
+        let state = desc.to_state(&classifier);
+        assert_eq!(state.language.as_deref(), Some("ko"));
+        assert_eq!(state.symbol, None);
+    }
+
+    fn build(type_name: &str, strings: &[&str], rank: Option<u32>) -> Box<dyn RefArg> {
+        let mut fields: VecDeque<Box<dyn RefArg>> = VecDeque::new();
+        fields.push_back(Box::new(type_name.to_owned()));
+        fields.push_back(Box::new(dbus::arg::PropMap::new()));
+        for s in strings {
+            fields.push_back(Box::new(s.to_string()));
+        }
+        if let Some(rank) = rank {
+            fields.push_back(Box::new(rank));
+        }
+
+        Box::new(fields)
+    }
+
+    #[test]
+    fn switched_state_keeps_desc_fields() {
+        let classifier = EngineClassifier::builtin();
+        let mozc =
+            read_global_engine(include_bytes!("testdata/global_engine_mozc_jp.bin")).unwrap();
+        let xkb_us =
+            read_global_engine(include_bytes!("testdata/global_engine_xkb_us.bin")).unwrap();
+        let mut cache = EngineDescCache::new([xkb_us]);
+
+        // 起動時の状態と同じく表示名・記号を持つため、同じエンジンへの切り替えは変更とならない
+        let state = cache.state("mozc-jp".to_owned(), &classifier, || Some(mozc.clone()));
+        assert_eq!(state, mozc.to_state(&classifier));
+        assert_eq!(state.display_name.as_deref(), Some("Mozc"));
+        assert_eq!(state.symbol.as_deref(), Some("あ"));
+
+        // 一度取得したエンジンは問い合わせない
+        let state = cache.state("mozc-jp".to_owned(), &classifier, || unreachable!());
+        assert_eq!(state.symbol.as_deref(), Some("あ"));
+
+        let state = cache.state("xkb:us::eng".to_owned(), &classifier, || unreachable!());
+        assert_eq!(state.display_name.as_deref(), Some("English (US)"));
+        assert_eq!(state.symbol.as_deref(), Some("en"));
+
+        // 取得できないか別のエンジンが返された場合は判定結果のみ
+        assert_eq!(
+            cache.state("anthy".to_owned(), &classifier, || None),
+            classifier.state("anthy")
+        );
+        assert_eq!(
+            cache.state("anthy".to_owned(), &classifier, || Some(mozc.clone())),
+            classifier.state("anthy")
+        );
+    }
+
+    #[test]
+    fn old_format_without_trailing_fields() {
+        let value = build(
+            TYPE_NAME,
+            &["anthy", "Anthy", "", "ja", "GPL", "", "", "jp"],
+            Some(0),
+        );
+
+        let desc = EngineDesc::from_ref_arg(&value).unwrap();
+        assert_eq!(desc.name, "anthy");
+        assert_eq!(desc.symbol, "");
+        assert_eq!(desc.icon_prop_key, "");
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        assert_eq!(
+            EngineDesc::from_ref_arg(&build("IBusText", &["a"; 8], Some(0))),
+            Err(EngineDescError::UnexpectedType("IBusText".into()))
+        );
+        assert_eq!(
+            EngineDesc::from_ref_arg(&build(TYPE_NAME, &["a"; 8], None)),
+            Err(EngineDescError::MissingField("rank"))
+        );
+        assert_eq!(
+            EngineDesc::from_ref_arg(&build(TYPE_NAME, &["a"; 9], None)),
+            Err(EngineDescError::InvalidField("rank"))
+        );
+        assert_eq!(
+            EngineDesc::from_ref_arg(&"mozc-jp".to_owned()),
+            Err(EngineDescError::NotStruct(ArgType::String))
+        );
+    }
+}
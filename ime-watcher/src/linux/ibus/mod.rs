@@ -0,0 +1,382 @@
+pub mod address;
+pub mod controller;
+pub mod engine_desc;
+
+pub use controller::{EngineHistory, IbusController};
+pub use engine_desc::{EngineDesc, EngineDescError};
+
+pub(crate) use engine_desc::EngineDescCache;
+
+use dbus::{
+    arg::{RefArg, Variant},
+    blocking::{Connection, Proxy, stdintf::org_freedesktop_dbus::Properties},
+    channel::{Channel, Token},
+    message::MatchRule,
+};
+
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{DbusSettings, ImeWatchError};
+use crate::{
+    Broadcaster, ChangeFilter, EngineClassifier, ImeEvent, ImeState, ImeWatcher, StopHandle,
+};
+
+pub(crate) const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(200);
+pub(crate) const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// IBusの`GlobalEngineChanged`シグナルを監視する。
+///
+/// ibus-daemonが再起動された場合は`BackendLost`を送り、アドレスを取得し直して再接続する。
+#[derive(Default)]
+pub struct IbusWatcher {
+    broadcaster: Arc<Broadcaster>,
+    classifier: Arc<EngineClassifier>,
+    settings: DbusSettings,
+    stop_handle: StopHandle,
+    thread: Option<JoinHandle<Result<(), ImeWatchError>>>,
+}
+
+impl IbusWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// エンジン名の判定に用いる。
+    pub fn with_classifier(mut self, classifier: EngineClassifier) -> Self {
+        self.classifier = Arc::new(classifier);
+        self
+    }
+
+    /// タイムアウトなどを指定する。
+    pub fn with_settings(mut self, settings: DbusSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+}
+
+/// IBusのバスのアドレスを取得する。
+pub(crate) fn ibus_address() -> Result<String, ImeWatchError> {
+    Ok(address::ibus_address()?)
+}
+
+/// IBusのバスに接続する。
+fn connect() -> Result<Connection, ImeWatchError> {
+    let address = ibus_address()?;
+
+    // IBusのバスもメッセージバスと同様に`Hello`による登録が必要。
+    // アドレスファイルが古くソケットがない場合はibus-daemonが起動していないものとする
+    let mut channel = Channel::open_private(&address)
+        .map_err(|e| ImeWatchError::BackendNotRunning(e.to_string()))?;
+    channel.register()?;
+
+    Ok(channel.into())
+}
+
+/// IBusの`/org/freedesktop/IBus`
+fn ibus_proxy(conn: &Connection, timeout: Duration) -> Proxy<'_, &Connection> {
+    conn.with_proxy("org.freedesktop.IBus", "/org/freedesktop/IBus", timeout)
+}
+
+/// インストールされているエンジン。読み取れないエンジンは飛ばす。
+///
+/// `Engines`プロパティがない古いIBusでは`ListEngines`を呼ぶ。
+fn engine_descs(proxy: &Proxy<'_, &Connection>) -> Result<Vec<EngineDesc>, ImeWatchError> {
+    let engines: Vec<Variant<Box<dyn RefArg>>> = match proxy.get("org.freedesktop.IBus", "Engines")
+    {
+        Ok(engines) => engines,
+        Err(e) => match ImeWatchError::from(e) {
+            ImeWatchError::ProtocolMismatch(_) => proxy
+                .method_call("org.freedesktop.IBus", "ListEngines", ())
+                .map(|(engines,)| engines)?,
+            e => return Err(e),
+        },
+    };
+
+    Ok(engines
+        .iter()
+        .filter_map(|engine| EngineDesc::from_ref_arg(engine).ok())
+        .collect())
+}
+
+/// 現在のグローバルエンジン。まだ設定されていない場合は`None`
+fn global_engine(
+    conn: &Connection,
+    timeout: Duration,
+) -> Result<Option<EngineDesc>, ImeWatchError> {
+    let engine = ibus_proxy(conn, timeout)
+        .get::<Variant<Box<dyn RefArg>>>("org.freedesktop.IBus", "GlobalEngine");
+
+    match engine {
+        Ok(engine) => EngineDesc::from_ref_arg(&engine)
+            .map(Some)
+            .map_err(|e| ImeWatchError::ProtocolMismatch(e.to_string())),
+        // エンジンが未設定の場合は`Failed`で応答される
+        Err(e) if e.name() == Some("org.freedesktop.DBus.Error.Failed") => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 利用可能なエンジンの一覧。読み取れないエンジンは飛ばす。
+pub fn available_engines(
+    classifier: &EngineClassifier,
+    settings: &DbusSettings,
+) -> Result<Vec<ImeState>, ImeWatchError> {
+    let conn = connect()?;
+
+    Ok(engine_descs(&ibus_proxy(&conn, settings.timeout))?
+        .iter()
+        .map(|desc| desc.to_state(classifier))
+        .collect())
+}
+
+/// 購読中のIBusへの接続
+struct IbusConnection {
+    conn: Connection,
+    token: Token,
+}
+
+impl IbusConnection {
+    /// アドレスを取得して接続し、`GlobalEngineChanged`を購読する。
+    fn open(
+        filter: &Arc<ChangeFilter>,
+        classifier: &Arc<EngineClassifier>,
+        timeout: Duration,
+    ) -> Result<Self, ImeWatchError> {
+        let conn = connect()?;
+
+        let proxy = ibus_proxy(&conn, timeout);
+
+        // 一覧を取得できなくても、切り替えのたびに`GlobalEngine`から補う
+        let mut descs = EngineDescCache::new(engine_descs(&proxy).unwrap_or_default());
+
+        let signal_mr = MatchRule::new_signal("org.freedesktop.IBus", "GlobalEngineChanged");
+
+        let token = proxy.match_start(
+            signal_mr,
+            true,
+            Box::new({
+                let filter = filter.clone();
+                let classifier = classifier.clone();
+
+                move |message, conn| {
+                    // 不正なシグナルは無視する
+                    if let Ok(engine_name) = message.read1::<String>() {
+                        filter.publish_state(descs.state(engine_name, &classifier, || {
+                            global_engine(conn, timeout).ok().flatten()
+                        }));
+                    }
+
+                    true
+                }
+            }),
+        )?;
+
+        Ok(Self { conn, token })
+    }
+
+    /// 現在の状態。エンジン未設定の場合と取得できない場合は`None`
+    fn current_state(&self, classifier: &EngineClassifier, timeout: Duration) -> Option<ImeState> {
+        global_engine(&self.conn, timeout)
+            .ok()
+            .flatten()
+            .map(|desc| desc.to_state(classifier))
+    }
+
+    /// 購読後に現在の状態を配信する。
+    fn publish_current(
+        &self,
+        filter: &ChangeFilter,
+        classifier: &EngineClassifier,
+        timeout: Duration,
+    ) {
+        if let Some(state) = self.current_state(classifier, timeout) {
+            filter.publish_state(state);
+        }
+    }
+
+    fn close(self) -> Result<(), ImeWatchError> {
+        Ok(self.conn.remove_match(self.token)?)
+    }
+}
+
+/// 停止されるまで待ちながら再接続を試みる。停止された場合は`None`
+fn reconnect(
+    stop_handle: &StopHandle,
+    filter: &Arc<ChangeFilter>,
+    classifier: &Arc<EngineClassifier>,
+    timeout: Duration,
+) -> Option<IbusConnection> {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+
+    while stop_handle.is_running() {
+        let wait_start = Instant::now();
+        while stop_handle.is_running() && wait_start.elapsed() < backoff {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        // `ibus restart`の直後はアドレスが更新されていないことがあるため、毎回取得し直す
+        if let Ok(ibus_conn) = IbusConnection::open(filter, classifier, timeout) {
+            return Some(ibus_conn);
+        }
+
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+
+    None
+}
+
+/// `BackendLost`を送り、`reconnect`で再接続できれば`BackendRecovered`を送ってから
+/// `publish_current`で再接続後の状態を配信する。
+fn recover<C>(
+    filter: &ChangeFilter,
+    reconnect: impl FnOnce() -> Option<C>,
+    publish_current: impl FnOnce(&C),
+) -> Option<C> {
+    filter.send(ImeEvent::BackendLost);
+
+    let conn = reconnect()?;
+    filter.send(ImeEvent::BackendRecovered);
+    publish_current(&conn);
+
+    Some(conn)
+}
+
+fn process_while_running(
+    mut ibus_conn: IbusConnection,
+    stop_handle: &StopHandle,
+    filter: &Arc<ChangeFilter>,
+    classifier: &Arc<EngineClassifier>,
+    settings: &DbusSettings,
+) -> Result<(), ImeWatchError> {
+    while stop_handle.is_running() {
+        let timeout = filter.wait_timeout(settings.process_interval);
+
+        // ibus-daemonが終了するとソケットが閉じられてエラーとなる
+        if ibus_conn.conn.process(timeout).is_err() {
+            let recovered = recover(
+                filter,
+                || reconnect(stop_handle, filter, classifier, settings.timeout),
+                |new_conn| new_conn.publish_current(filter, classifier, settings.timeout),
+            );
+
+            match recovered {
+                Some(new_conn) => ibus_conn = new_conn,
+                None => return Ok(()),
+            }
+        }
+
+        filter.tick();
+    }
+
+    ibus_conn.close()
+}
+
+impl ImeWatcher for IbusWatcher {
+    type Error = ImeWatchError;
+
+    fn start(&mut self) -> Result<(), ImeWatchError> {
+        if self.stop_handle.is_running() {
+            return Ok(());
+        }
+
+        // 停止済みのスレッドが残っていれば回収する。前回の監視の失敗は`stop`を呼んだ側にのみ返し、
+        // 新しい監視の開始を妨げない
+        let _ = self.stop();
+
+        let filter = Arc::new(ChangeFilter::new(
+            self.broadcaster.clone(),
+            self.settings.emit,
+        ));
+        let ibus_conn = IbusConnection::open(&filter, &self.classifier, self.settings.timeout)?;
+        ibus_conn.publish_current(&filter, &self.classifier, self.settings.timeout);
+
+        self.stop_handle.set_running();
+
+        self.thread = Some(std::thread::spawn({
+            let stop_handle = self.stop_handle.clone();
+            let broadcaster = self.broadcaster.clone();
+            let classifier = self.classifier.clone();
+            let settings = self.settings.clone();
+
+            move || {
+                let res =
+                    process_while_running(ibus_conn, &stop_handle, &filter, &classifier, &settings);
+
+                stop_handle.stop();
+                broadcaster.close();
+                res
+            }
+        }));
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), ImeWatchError> {
+        self.stop_handle.stop();
+
+        match self.thread.take() {
+            Some(thread) => thread.join().expect("ibus watcher thread panicked"),
+            None => Ok(()),
+        }
+    }
+
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    fn current_state(&self) -> Option<ImeState> {
+        self.broadcaster.current_state()
+    }
+
+    fn subscribe(&self) -> Receiver<ImeEvent> {
+        self.broadcaster.subscribe()
+    }
+}
+
+impl Drop for IbusWatcher {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EmitPolicy;
+
+    #[test]
+    fn recovered_comes_before_the_state_after_reconnecting() {
+        let broadcaster = Arc::new(Broadcaster::new());
+        let events = broadcaster.subscribe();
+        let filter = ChangeFilter::new(broadcaster, EmitPolicy::OnChange);
+        let state = ImeState::new("mozc-jp");
+
+        filter.publish_state(state.clone());
+        recover(
+            &filter,
+            || Some(()),
+            |_| filter.publish_state(state.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                ImeEvent::Changed(state.clone()),
+                ImeEvent::BackendLost,
+                ImeEvent::BackendRecovered,
+                ImeEvent::Changed(state),
+            ]
+        );
+
+        // 再接続できなければ`BackendLost`のみ
+        assert!(recover(&filter, || None::<()>, |_| unreachable!()).is_none());
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [ImeEvent::BackendLost]
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! D-Bus経由でfcitx5/IBusを監視するバックエンド。
+
+pub mod detect;
+pub mod error;
+pub mod fcitx5;
+pub mod focus;
+pub mod ibus;
+pub mod service;
+pub mod settings;
+#[cfg(feature = "tokio")]
+pub mod stream;
+
+pub use detect::{Backend, Detection, detect_backend};
+pub use error::ImeWatchError;
+pub use fcitx5::Fcitx5Watcher;
+pub use ibus::IbusWatcher;
+pub use service::StateService;
+pub use settings::DbusSettings;
+
+use dbus::channel::{BusType, Channel};
+
+/// セッションバスに接続する
+pub fn session_channel() -> Result<Channel, ImeWatchError> {
+    Channel::get_private(BusType::Session).map_err(ImeWatchError::NoSessionBus)
+}
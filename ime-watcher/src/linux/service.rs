@@ -0,0 +1,352 @@
+//! fcitx5/IBusの違いを吸収した状態をセッションバスに公開する。
+//!
+//! `org.imewatcher.Watcher`という名前で`/org/imewatcher/Watcher`に
+//! `org.imewatcher.Watcher1`インターフェースを持つオブジェクトを置く。
+//!
+//! - `CurrentState`プロパティ(`a{sv}`): 現在の状態。取得前は空
+//! - `StateChanged`シグナル(`a{sv}`): 変更後の状態
+//!
+//! 状態の辞書は`engine`, `kind`, `active`を必ず含み、`display_name`, `language`,
+//! `symbol`, `open`は分かる場合のみ含む。
+
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::{Connection, stdintf::org_freedesktop_dbus::RequestNameReply};
+use dbus::channel::{Channel, MatchingReceiver, Sender, Token};
+use dbus::message::{MatchRule, Message, MessageType};
+use dbus::strings::ErrorName;
+
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::ImeWatchError;
+use crate::{ImeEvent, ImeState};
+
+pub const BUS_NAME: &str = "org.imewatcher.Watcher";
+pub const OBJECT_PATH: &str = "/org/imewatcher/Watcher";
+pub const INTERFACE: &str = "org.imewatcher.Watcher1";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.imewatcher.Watcher1">
+    <property name="CurrentState" type="a{sv}" access="read">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="true"/>
+    </property>
+    <signal name="StateChanged">
+      <arg name="state" type="a{sv}"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/// 状態を`a{sv}`にする。
+pub fn state_to_prop_map(state: &ImeState) -> PropMap {
+    let mut map = PropMap::new();
+
+    let mut insert_string = |key: &str, value: &str| {
+        map.insert(key.to_owned(), Variant(Box::new(value.to_owned())));
+    };
+
+    insert_string("engine", &state.engine);
+    insert_string("kind", &state.kind.to_string());
+    if let Some(display_name) = &state.display_name {
+        insert_string("display_name", display_name);
+    }
+    if let Some(language) = &state.language {
+        insert_string("language", language);
+    }
+    if let Some(symbol) = &state.symbol {
+        insert_string("symbol", symbol);
+    }
+
+    if let Some(open) = state.open {
+        map.insert("open".to_owned(), Variant(Box::new(open)));
+    }
+    map.insert("active".to_owned(), Variant(Box::new(state.is_active())));
+
+    map
+}
+
+fn error_reply(message: &Message, name: &'static str, reason: &str) -> Message {
+    let reason = CString::new(reason).unwrap_or_default();
+
+    message.error(&ErrorName::from(name), &reason)
+}
+
+/// メソッド呼び出しに対する返信
+fn method_reply(message: &Message, state: &Mutex<Option<ImeState>>) -> Message {
+    let current_state = || {
+        state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(state_to_prop_map)
+            .unwrap_or_default()
+    };
+
+    let interface = message.interface();
+    let member = message.member();
+
+    match (interface.as_deref(), member.as_deref()) {
+        (Some("org.freedesktop.DBus.Introspectable"), Some("Introspect")) => {
+            message.method_return().append1(INTROSPECTION)
+        }
+        (Some("org.freedesktop.DBus.Properties"), Some("Get")) => {
+            match message.read2::<&str, &str>() {
+                Ok((INTERFACE, "CurrentState")) => {
+                    message.method_return().append1(Variant(current_state()))
+                }
+                Ok((interface, property)) => error_reply(
+                    message,
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    &format!("{interface}.{property} is not found"),
+                ),
+                Err(e) => error_reply(
+                    message,
+                    "org.freedesktop.DBus.Error.InvalidArgs",
+                    &e.to_string(),
+                ),
+            }
+        }
+        (Some("org.freedesktop.DBus.Properties"), Some("GetAll")) => {
+            match message.read1::<&str>() {
+                Ok(INTERFACE) => {
+                    let mut properties = PropMap::new();
+                    properties.insert(
+                        "CurrentState".to_owned(),
+                        Variant(Box::new(current_state())),
+                    );
+
+                    message.method_return().append1(properties)
+                }
+                Ok(_) => message.method_return().append1(PropMap::new()),
+                Err(e) => error_reply(
+                    message,
+                    "org.freedesktop.DBus.Error.InvalidArgs",
+                    &e.to_string(),
+                ),
+            }
+        }
+        (Some("org.freedesktop.DBus.Properties"), Some("Set")) => error_reply(
+            message,
+            "org.freedesktop.DBus.Error.PropertyReadOnly",
+            "CurrentState is read-only",
+        ),
+        (interface, member) => error_reply(
+            message,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            &format!(
+                "{}.{} is not found",
+                interface.unwrap_or_default(),
+                member.unwrap_or_default()
+            ),
+        ),
+    }
+}
+
+fn signal(interface: &'static str, member: &'static str) -> Message {
+    Message::new_signal(OBJECT_PATH, interface, member).expect("signal names are valid")
+}
+
+/// 監視のイベントを`org.imewatcher.Watcher1`として公開する。
+pub struct StateService {
+    conn: Connection,
+    state: Arc<Mutex<Option<ImeState>>>,
+    token: Token,
+}
+
+impl StateService {
+    /// `channel`のバスで`org.imewatcher.Watcher`を取得する。既に取得されている場合は失敗する
+    ///
+    /// 接続は呼び出し側が用意する。セッションバスであれば[`super::session_channel`]で開ける。
+    pub fn register(mut channel: Channel) -> Result<Self, ImeWatchError> {
+        // バスとイベントを同時に待つため、接続のファイル記述子を使う
+        channel.set_watch_enabled(true);
+        let conn = Connection::from(channel);
+
+        // 他のインスタンスがいる場合は待たずに失敗する
+        let name_reply = conn.request_name(BUS_NAME, false, false, true)?;
+        if name_reply != RequestNameReply::PrimaryOwner {
+            return Err(ImeWatchError::NameTaken(BUS_NAME.to_owned()));
+        }
+
+        let state = Arc::new(Mutex::new(None));
+
+        let mut method_mr = MatchRule::new_method_call();
+        method_mr.path = Some(OBJECT_PATH.into());
+
+        let token = conn.start_receive(
+            method_mr,
+            Box::new({
+                let state = state.clone();
+
+                move |message, conn| {
+                    if message.msg_type() == MessageType::MethodCall && !message.get_no_reply() {
+                        let _ = conn.send(method_reply(&message, &state));
+                    }
+
+                    true
+                }
+            }),
+        );
+
+        Ok(Self { conn, state, token })
+    }
+
+    fn publish(&self, state: ImeState) -> Result<(), ImeWatchError> {
+        let map = state_to_prop_map(&state);
+        *self.state.lock().unwrap() = Some(state);
+
+        let state_changed = signal(INTERFACE, "StateChanged").append1(&map);
+
+        let mut changed = PropMap::new();
+        changed.insert("CurrentState".to_owned(), Variant(Box::new(map)));
+
+        let properties_changed = signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .append3(INTERFACE, changed, Vec::<String>::new());
+
+        for signal in [state_changed, properties_changed] {
+            self.conn.send(signal).map_err(|_| {
+                ImeWatchError::Disconnected(dbus::Error::new_failed("failed to send a signal"))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// バスと`woken`を待ち、`woken`が閉じられるまでメソッド呼び出しと`pending`の状態を処理する
+    fn serve(
+        &self,
+        mut woken: UnixStream,
+        pending: &Mutex<Vec<ImeState>>,
+    ) -> Result<(), ImeWatchError> {
+        let bus = self.conn.channel().watch().fd;
+
+        loop {
+            // 読み込み済みのメッセージはファイル記述子を待っても通知されない
+            while self.conn.process(Duration::ZERO)? {}
+            self.conn.channel().flush();
+
+            let mut fds = [bus, woken.as_raw_fd()].map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                return Err(ImeWatchError::Io(e));
+            }
+
+            if fds[1].revents != 0 {
+                if woken.read(&mut [0; 64]).map_err(ImeWatchError::Io)? == 0 {
+                    return Ok(());
+                }
+
+                let states = std::mem::take(&mut *pending.lock().unwrap());
+                for state in states {
+                    self.publish(state)?;
+                }
+            }
+        }
+    }
+
+    /// `events`が切断されるまで公開する。監視を停止するとReceiverが切断されて戻る
+    ///
+    /// バスとイベントを同時に待ち、メソッド呼び出しにもイベントにもすぐ応じる。
+    ///
+    /// 公開に失敗した場合はイベントを待たずにすぐ戻る。
+    pub fn run(self, events: Receiver<ImeEvent>) -> Result<(), ImeWatchError> {
+        let (woken, mut wake) = UnixStream::pair().map_err(ImeWatchError::Io)?;
+        let pending = Arc::new(Mutex::new(Vec::new()));
+
+        // 状態を溜めて起こす。`events`が切断されると`wake`が閉じられる。
+        // 公開側が先に終了すると`woken`が閉じられ、次のイベントで書き込みに失敗して終わる
+        std::thread::spawn({
+            let pending = pending.clone();
+
+            move || {
+                for event in events {
+                    match event {
+                        ImeEvent::Changed(state) => pending.lock().unwrap().push(state),
+                        // 接続の状態とグループは公開しない。再接続後に`Changed`が送られる
+                        ImeEvent::BackendLost
+                        | ImeEvent::BackendRecovered
+                        | ImeEvent::GroupChanged(_) => continue,
+                    }
+
+                    if wake.write_all(&[0]).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let res = self.serve(woken, &pending);
+
+        // 失敗した場合も名前を手放す。接続が切れていれば手放せないが、名前も既にない
+        self.conn.stop_receive(self.token);
+        let released = self.conn.release_name(BUS_NAME);
+
+        res?;
+        released?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputKind;
+    use dbus::arg::RefArg;
+
+    #[test]
+    fn optional_fields_are_omitted() {
+        let state = ImeState::new("mozc")
+            .with_kind(InputKind::Composing)
+            .with_language("ja")
+            .with_open(false);
+
+        let map = state_to_prop_map(&state);
+
+        let mut keys: Vec<_> = map.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["active", "engine", "kind", "language", "open"]);
+
+        assert_eq!(map["engine"].0.as_str(), Some("mozc"));
+        assert_eq!(map["kind"].0.as_str(), Some("composing"));
+        assert_eq!(map["open"].0.as_u64(), Some(0));
+        assert_eq!(map["active"].0.as_u64(), Some(0));
+    }
+}
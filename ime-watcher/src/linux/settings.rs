@@ -0,0 +1,40 @@
+//! D-Busのバックエンドで共通の調整項目。
+
+use std::time::Duration;
+
+use crate::change::EmitPolicy;
+use crate::config::Config;
+use crate::debounce::DebounceSettings;
+
+/// 問い合わせのタイムアウトなど。既定値は[`Config`]の既定値と同じ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbusSettings {
+    /// メソッド呼び出しとプロパティ取得のタイムアウト
+    pub timeout: Duration,
+    /// 停止要求やバックエンドの再起動を確認する間隔
+    pub process_interval: Duration,
+    /// fcitx5のStatusNotifierItemの`Id`
+    pub sni_id: String,
+    /// 同じ状態の繰り返しを配信するか
+    pub emit: EmitPolicy,
+    /// 続けて届くシグナルをまとめる条件
+    pub debounce: DebounceSettings,
+}
+
+impl Default for DbusSettings {
+    fn default() -> Self {
+        Self::from(&Config::default())
+    }
+}
+
+impl From<&Config> for DbusSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            timeout: config.dbus_timeout,
+            process_interval: config.process_interval,
+            sni_id: config.sni_id.clone(),
+            emit: config.emit,
+            debounce: config.debounce,
+        }
+    }
+}
@@ -0,0 +1,685 @@
+//! tokio上で動作する非同期版のバックエンド。
+//!
+//! ブロッキング版の[`Fcitx5Watcher`](super::Fcitx5Watcher)(`Fcitx5Mode::Auto`)・
+//! [`IbusWatcher`](super::IbusWatcher)と同じシグナルを購読し、変更を`Stream<Item = ImeEvent>`
+//! として返す。バックエンドが再起動された場合は`BackendLost`・`BackendRecovered`を送って
+//! 購読し直し、fcitx5のシグナルは[`Debouncer`]でまとめる。セッションバスとの接続が
+//! 切断されるとストリームは終了する。同じ状態の繰り返しは[`DbusSettings::emit`]に従って取り除く。
+
+use dbus::arg::{RefArg, Variant};
+use dbus::channel::Channel;
+use dbus::message::{MatchRule, Message};
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection, stdintf::org_freedesktop_dbus::Properties};
+use dbus_tokio::connection::{IOResource, IOResourceError};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures_util::stream::{BoxStream, Stream, StreamExt};
+use tokio::task::JoinHandle;
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use super::fcitx5::{
+    CONTROLLER_INTERFACE, FCITX5_BUS_NAME, Fcitx5Mode, GroupTracker, KIMPANEL_BUS_NAME,
+    NATIVE_SIGNALS, POLL_INTERVAL, SNI_WATCHER_BUS_NAME, backend_transition, needs_polling,
+    open_state,
+};
+use super::ibus::{EngineDesc, EngineDescCache, RECONNECT_BACKOFF_MAX, RECONNECT_BACKOFF_MIN};
+use super::{DbusSettings, ImeWatchError};
+use crate::debounce::Debouncer;
+use crate::{ChangeDetector, EmitPolicy, EngineClassifier, ImeEvent, ImeState};
+
+/// 破棄とともに接続を閉じる。
+struct ConnectionGuard {
+    conn: Arc<SyncConnection>,
+    resource: JoinHandle<IOResourceError>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.resource.abort();
+    }
+}
+
+impl ConnectionGuard {
+    fn new(resource: IOResource<SyncConnection>, conn: Arc<SyncConnection>) -> Self {
+        Self {
+            conn,
+            resource: tokio::spawn(resource),
+        }
+    }
+
+    /// 次のシグナルを待つ。接続が切断された場合は`None`
+    async fn next_signal<T>(
+        &mut self,
+        receiver: &mut (impl Stream<Item = T> + Unpin),
+    ) -> Option<T> {
+        tokio::select! {
+            _ = &mut self.resource => None,
+            message = receiver.next() => message,
+        }
+    }
+}
+
+/// 監視するタスクが送るイベントのストリーム。破棄するとタスクも止める
+struct TaskStream {
+    events: UnboundedReceiver<ImeEvent>,
+    task: JoinHandle<()>,
+}
+
+impl TaskStream {
+    /// `watch`を`events`が破棄されるか`None`を返すまで実行する。
+    fn spawn<F>(watch: impl FnOnce(UnboundedSender<ImeEvent>) -> F) -> Self
+    where
+        F: Future<Output = Option<()>> + Send + 'static,
+    {
+        let (sender, events) = unbounded();
+        let watch = watch(sender);
+
+        Self {
+            events,
+            task: tokio::spawn(async move {
+                watch.await;
+            }),
+        }
+    }
+}
+
+impl Stream for TaskStream {
+    type Item = ImeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ImeEvent>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for TaskStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 変更の判定を通す。ハートビートは次のイベントを待つ間に送る
+fn detect_changes(
+    events: BoxStream<'static, ImeEvent>,
+    policy: EmitPolicy,
+) -> BoxStream<'static, ImeEvent> {
+    futures_util::stream::unfold(
+        (events, ChangeDetector::new(policy)),
+        |(mut events, mut detector)| async move {
+            loop {
+                let event = match detector.until_heartbeat(Instant::now()) {
+                    Some(wait) => tokio::select! {
+                        event = events.next() => event?,
+                        _ = tokio::time::sleep(wait) => {
+                            // ハートビートは同じ状態の再送なので判定を通さない
+                            match detector.heartbeat(Instant::now()) {
+                                Some(state) => {
+                                    return Some((ImeEvent::Changed(state), (events, detector)));
+                                }
+                                None => continue,
+                            }
+                        }
+                    },
+                    None => events.next().await?,
+                };
+
+                if let Some(event) = detector.filter(event, Instant::now()) {
+                    return Some((event, (events, detector)));
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// fcitx5の監視で受け取るシグナル
+#[derive(Debug, Clone, Copy)]
+enum Fcitx5Signal {
+    /// 入力メソッドが切り替わった可能性がある
+    Changed,
+    /// `Controller1`からグループの変更が通知された
+    GroupsChanged,
+    /// fcitx5かStatusNotifierItem、kimpanelのパネルが入れ替わった可能性がある
+    Resync,
+    /// シグナルが届かない間に`CurrentInputMethod`を確認する時刻になった
+    Poll,
+}
+
+/// `rule`に一致するシグナルを`signal`として`signals`に送る。
+async fn match_signal(
+    conn: &SyncConnection,
+    rule: MatchRule<'static>,
+    signals: &UnboundedSender<Fcitx5Signal>,
+    signal: Fcitx5Signal,
+) -> Result<MsgMatch, dbus::Error> {
+    let signals = signals.clone();
+
+    Ok(conn
+        .add_match(rule)
+        .await?
+        .msg_cb(move |_| signals.unbounded_send(signal).is_ok()))
+}
+
+/// fcitx5のStatusNotifierItemを探す。
+async fn find_fcitx5_sni(
+    conn: &SyncConnection,
+    settings: &DbusSettings,
+) -> Result<Option<(String, String)>, dbus::Error> {
+    let notifier_watcher_proxy = Proxy::new(
+        SNI_WATCHER_BUS_NAME,
+        "/StatusNotifierWatcher",
+        settings.timeout,
+        conn,
+    );
+
+    let notifier_items: Vec<String> = notifier_watcher_proxy
+        .get(
+            "org.kde.StatusNotifierWatcher",
+            "RegisteredStatusNotifierItems",
+        )
+        .await?;
+
+    for sni_name in notifier_items.into_iter() {
+        let Some((dest, path)) = sni_name.split_once("@") else {
+            continue;
+        };
+
+        let sni_proxy = Proxy::new(dest, path, settings.timeout, conn);
+
+        // 終了済みのアイテムが残っている場合があるため、取得できないものは飛ばす
+        let Ok(sni_id) = sni_proxy
+            .get::<String>("org.kde.StatusNotifierItem", "Id")
+            .await
+        else {
+            continue;
+        };
+
+        if sni_id == settings.sni_id {
+            return Ok(Some((dest.to_owned(), path.to_owned())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// `name`を所有するユニーク名。所有されていない場合は`None`
+async fn name_owner(conn: &SyncConnection, name: &str, timeout: Duration) -> Option<String> {
+    Proxy::new(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        timeout,
+        conn,
+    )
+    .method_call("org.freedesktop.DBus", "GetNameOwner", (name,))
+    .await
+    .ok()
+    .map(|(owner,): (String,)| owner)
+}
+
+/// fcitx5への購読。ブロッキング版の`Fcitx5Mode::Auto`と同じく、fcitx5自身のシグナルと
+/// StatusNotifierItemの`NewIcon`を購読する
+struct Fcitx5Subscription {
+    conn: Arc<SyncConnection>,
+    settings: DbusSettings,
+    signals: UnboundedSender<Fcitx5Signal>,
+    /// fcitx5のユニーク名
+    backend: Option<String>,
+    item: Option<(String, String)>,
+    new_icon: Option<MsgMatch>,
+    /// シグナルが届かないため`CurrentInputMethod`を確認しているか
+    polling: bool,
+    _msg_matches: Vec<MsgMatch>,
+}
+
+impl Fcitx5Subscription {
+    /// fcitx5自身のシグナルと、fcitx5・StatusNotifierWatcherの再起動やアイテムの登録・解除を購読する。
+    async fn new(
+        conn: Arc<SyncConnection>,
+        settings: DbusSettings,
+        signals: UnboundedSender<Fcitx5Signal>,
+    ) -> Result<Self, dbus::Error> {
+        let mut msg_matches = Vec::new();
+
+        // 送信元はユニーク名になるため、well-known名では絞り込まない
+        for (interface, member) in NATIVE_SIGNALS {
+            let signal = match interface {
+                CONTROLLER_INTERFACE => Fcitx5Signal::GroupsChanged,
+                _ => Fcitx5Signal::Changed,
+            };
+
+            msg_matches.push(
+                match_signal(
+                    &conn,
+                    MatchRule::new_signal(interface, member),
+                    &signals,
+                    signal,
+                )
+                .await?,
+            );
+        }
+
+        let name_owner_mr = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+            .with_sender("org.freedesktop.DBus");
+
+        msg_matches.push(conn.add_match(name_owner_mr).await?.cb({
+            let signals = signals.clone();
+
+            move |_, (name, _old_owner, _new_owner): (String, String, String)| {
+                if [FCITX5_BUS_NAME, SNI_WATCHER_BUS_NAME, KIMPANEL_BUS_NAME]
+                    .contains(&name.as_str())
+                {
+                    signals.unbounded_send(Fcitx5Signal::Resync).is_ok()
+                } else {
+                    true
+                }
+            }
+        }));
+
+        for member in [
+            "StatusNotifierItemRegistered",
+            "StatusNotifierItemUnregistered",
+        ] {
+            msg_matches.push(
+                match_signal(
+                    &conn,
+                    MatchRule::new_signal(SNI_WATCHER_BUS_NAME, member),
+                    &signals,
+                    Fcitx5Signal::Resync,
+                )
+                .await?,
+            );
+        }
+
+        Ok(Self {
+            conn,
+            settings,
+            signals,
+            backend: None,
+            item: None,
+            new_icon: None,
+            polling: false,
+            _msg_matches: msg_matches,
+        })
+    }
+
+    /// StatusNotifierItemを探し直して`NewIcon`を購読し直し、fcitx5の入れ替わりを返す。
+    /// 定期的な確認が必要かも判定し直す
+    async fn resync(&mut self) -> Result<Vec<ImeEvent>, dbus::Error> {
+        // StatusNotifierWatcherが存在しない場合もアイテムがないものとして扱う
+        let item = find_fcitx5_sni(&self.conn, &self.settings)
+            .await
+            .unwrap_or(None);
+
+        if item != self.item {
+            if let Some(new_icon) = self.new_icon.take() {
+                self.conn.remove_match(new_icon.token()).await?;
+            }
+
+            if let Some((dest, path)) = &item {
+                let new_icon_mr = MatchRule::new_signal("org.kde.StatusNotifierItem", "NewIcon")
+                    .with_sender(dest.clone())
+                    .with_path(path.clone());
+
+                self.new_icon = Some(
+                    match_signal(
+                        &self.conn,
+                        new_icon_mr,
+                        &self.signals,
+                        Fcitx5Signal::Changed,
+                    )
+                    .await?,
+                );
+            }
+
+            self.item = item;
+        }
+
+        let timeout = self.settings.timeout;
+        let backend = name_owner(&self.conn, FCITX5_BUS_NAME, timeout).await;
+        let events = backend_transition(self.backend.as_deref(), backend.as_deref());
+        self.backend = backend;
+
+        let kimpanel = name_owner(&self.conn, KIMPANEL_BUS_NAME, timeout)
+            .await
+            .is_some();
+        self.polling = self.backend.is_some()
+            && needs_polling(Fcitx5Mode::Auto, kimpanel, self.item.is_some());
+
+        Ok(events)
+    }
+}
+
+/// fcitx5の状態を取得して送る。
+struct Fcitx5Publisher {
+    conn: Arc<SyncConnection>,
+    classifier: EngineClassifier,
+    timeout: Duration,
+    groups: GroupTracker,
+    groups_changed: bool,
+    /// 最後に確認した入力メソッドと`State`
+    polled: Option<(String, i32)>,
+    events: UnboundedSender<ImeEvent>,
+}
+
+impl Fcitx5Publisher {
+    fn send(&self, event: ImeEvent) -> Option<()> {
+        self.events.unbounded_send(event).ok()
+    }
+
+    /// 入力メソッドの名前と`State`
+    async fn input_method(&self) -> Result<(String, i32), dbus::Error> {
+        let controller_proxy =
+            Proxy::new(FCITX5_BUS_NAME, "/controller", self.timeout, &*self.conn);
+
+        let (ime_status,): (String,) = controller_proxy
+            .method_call(CONTROLLER_INTERFACE, "CurrentInputMethod", ())
+            .await?;
+
+        let (state,): (i32,) = controller_proxy
+            .method_call(CONTROLLER_INTERFACE, "State", ())
+            .await?;
+
+        Ok((ime_status, state))
+    }
+
+    async fn state(&self) -> Result<ImeState, dbus::Error> {
+        let (ime_status, state) = self.input_method().await?;
+
+        Ok(ImeState {
+            open: open_state(state),
+            ..self.classifier.state(ime_status)
+        })
+    }
+
+    /// 入力メソッドか`State`が前回の確認から変わったか
+    async fn poll(&mut self) -> bool {
+        match self.input_method().await {
+            Ok(polled) if self.polled.as_ref() != Some(&polled) => {
+                self.polled = Some(polled);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// グループが変わっていれば`GroupChanged`を、続けて現在の状態を送る。
+    ///
+    /// fcitx5の再起動中は失敗するが、それは`NameOwnerChanged`から`BackendLost`として送られる。
+    async fn publish(&mut self) -> Option<()> {
+        let notified = std::mem::take(&mut self.groups_changed);
+
+        let group: Result<(String,), _> =
+            Proxy::new(FCITX5_BUS_NAME, "/controller", self.timeout, &*self.conn)
+                .method_call(CONTROLLER_INTERFACE, "CurrentInputMethodGroup", ())
+                .await;
+
+        if let Ok((group,)) = group
+            && let Some(event) = self.groups.update(group, notified)
+        {
+            self.send(event)?;
+        }
+
+        if let Ok(state) = self.state().await {
+            self.send(ImeEvent::Changed(state))?;
+        }
+
+        Some(())
+    }
+}
+
+/// セッションバスとの接続が切断されるまで、シグナルをまとめて状態を送る。
+async fn watch_fcitx5(
+    mut guard: ConnectionGuard,
+    mut subscription: Fcitx5Subscription,
+    mut signals: UnboundedReceiver<Fcitx5Signal>,
+    mut publisher: Fcitx5Publisher,
+) -> Option<()> {
+    let mut debouncer = Debouncer::new(subscription.settings.debounce);
+
+    let mut next_poll = Instant::now();
+
+    // 最初のシグナルを待たずに現在の状態を送る
+    let mut publish = debouncer.notify(Instant::now());
+
+    loop {
+        // 通知が絶え間なく届いても最長待ち時間を守る
+        if publish || debouncer.poll(Instant::now()) {
+            publisher.publish().await?;
+        }
+
+        let deadline = debouncer.deadline();
+        let signal = tokio::select! {
+            signal = guard.next_signal(&mut signals) => Some(signal?),
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                if deadline.is_some() => None,
+            _ = tokio::time::sleep_until(next_poll.into()),
+                if subscription.polling => Some(Fcitx5Signal::Poll),
+        };
+
+        publish = match signal {
+            Some(Fcitx5Signal::Changed) => debouncer.notify(Instant::now()),
+            Some(Fcitx5Signal::GroupsChanged) => {
+                publisher.groups_changed = true;
+                debouncer.notify(Instant::now())
+            }
+            Some(Fcitx5Signal::Resync) => {
+                let events = subscription.resync().await.ok()?;
+                let recovered = events.last() == Some(&ImeEvent::BackendRecovered);
+                publisher.polled = None;
+
+                for event in events {
+                    publisher.send(event)?;
+                }
+
+                // 再起動後の状態を取得し直す
+                recovered && debouncer.notify(Instant::now())
+            }
+            Some(Fcitx5Signal::Poll) => {
+                next_poll = Instant::now() + POLL_INTERVAL;
+
+                publisher.poll().await && debouncer.notify(Instant::now())
+            }
+            None => debouncer.poll(Instant::now()),
+        };
+    }
+}
+
+/// fcitx5の変更をfcitx5自身のシグナルを契機に取得するストリーム。
+///
+/// StatusNotifierItemが見つかれば`NewIcon`も併用する。
+pub async fn fcitx5_stream(
+    classifier: EngineClassifier,
+    settings: DbusSettings,
+) -> Result<BoxStream<'static, ImeEvent>, ImeWatchError> {
+    let (resource, conn) =
+        dbus_tokio::connection::new_session_sync().map_err(ImeWatchError::NoSessionBus)?;
+    let guard = ConnectionGuard::new(resource, conn);
+
+    let (signal_sender, signals) = unbounded();
+    let mut subscription =
+        Fcitx5Subscription::new(guard.conn.clone(), settings.clone(), signal_sender).await?;
+
+    // 起動時の`BackendRecovered`は配信しない
+    subscription.resync().await?;
+
+    if subscription.backend.is_none() {
+        return Err(ImeWatchError::BackendNotRunning(
+            "fcitx5 is not running".to_owned(),
+        ));
+    }
+
+    let emit = settings.emit;
+    let stream = TaskStream::spawn(|events| {
+        let publisher = Fcitx5Publisher {
+            conn: guard.conn.clone(),
+            classifier,
+            timeout: settings.timeout,
+            groups: GroupTracker::default(),
+            groups_changed: false,
+            polled: None,
+            events,
+        };
+
+        watch_fcitx5(guard, subscription, signals, publisher)
+    });
+
+    Ok(detect_changes(stream.boxed(), emit))
+}
+
+/// 購読中のIBusへの接続
+struct IbusConnection {
+    guard: ConnectionGuard,
+    signals: UnboundedReceiver<Message>,
+    _msg_match: MsgMatch,
+    timeout: Duration,
+    descs: EngineDescCache,
+}
+
+impl IbusConnection {
+    /// アドレスを取得して接続し、`GlobalEngineChanged`を購読する。
+    async fn open(timeout: Duration) -> Result<Self, ImeWatchError> {
+        let address = super::ibus::ibus_address()?;
+
+        let mut channel = Channel::open_private(&address)
+            .map_err(|e| ImeWatchError::BackendNotRunning(e.to_string()))?;
+        channel.register()?;
+        let (resource, conn) = dbus_tokio::connection::from_channel::<SyncConnection>(channel)?;
+        let guard = ConnectionGuard::new(resource, conn);
+
+        let signal_mr = MatchRule::new_signal("org.freedesktop.IBus", "GlobalEngineChanged");
+        let (msg_match, signals) = guard.conn.add_match(signal_mr).await?.msg_stream();
+
+        let mut ibus_conn = Self {
+            guard,
+            signals,
+            _msg_match: msg_match,
+            timeout,
+            descs: EngineDescCache::default(),
+        };
+        // 一覧を取得できなくても、切り替えのたびに`GlobalEngine`から補う
+        ibus_conn.descs = EngineDescCache::new(ibus_conn.engine_descs().await);
+
+        Ok(ibus_conn)
+    }
+
+    fn ibus_proxy(&self) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(
+            "org.freedesktop.IBus",
+            "/org/freedesktop/IBus",
+            self.timeout,
+            &*self.guard.conn,
+        )
+    }
+
+    /// インストールされているエンジン。取得できなければ空とする
+    ///
+    /// `Engines`プロパティがない古いIBusでは`ListEngines`を呼ぶ。
+    async fn engine_descs(&self) -> Vec<EngineDesc> {
+        let proxy = self.ibus_proxy();
+
+        let engines: Vec<Variant<Box<dyn RefArg>>> =
+            match proxy.get("org.freedesktop.IBus", "Engines").await {
+                Ok(engines) => engines,
+                Err(_) => proxy
+                    .method_call("org.freedesktop.IBus", "ListEngines", ())
+                    .await
+                    .map(|(engines,)| engines)
+                    .unwrap_or_default(),
+            };
+
+        engines
+            .iter()
+            .filter_map(|engine| EngineDesc::from_ref_arg(engine).ok())
+            .collect()
+    }
+
+    /// 現在のグローバルエンジン。エンジン未設定の場合は失敗するため`None`とする
+    async fn global_engine(&self) -> Option<EngineDesc> {
+        let global_engine: Variant<Box<dyn RefArg>> = self
+            .ibus_proxy()
+            .get("org.freedesktop.IBus", "GlobalEngine")
+            .await
+            .ok()?;
+
+        EngineDesc::from_ref_arg(&global_engine).ok()
+    }
+
+    async fn current_state(&self, classifier: &EngineClassifier) -> Option<ImeState> {
+        Some(self.global_engine().await?.to_state(classifier))
+    }
+
+    /// 次に切り替わったエンジンの状態。切断された場合は`None`
+    async fn next_state(&mut self, classifier: &EngineClassifier) -> Option<ImeState> {
+        loop {
+            let message = self.guard.next_signal(&mut self.signals).await?;
+
+            // 不正なシグナルは無視する
+            if let Ok(engine_name) = message.read1::<String>() {
+                // 一覧にないエンジンは`GlobalEngine`から補う
+                let fetched = if self.descs.contains(&engine_name) {
+                    None
+                } else {
+                    self.global_engine().await
+                };
+
+                return Some(self.descs.state(engine_name, classifier, || fetched));
+            }
+        }
+    }
+}
+
+/// 接続できるまで待ちながら再接続を試みる。
+async fn reconnect_ibus(timeout: Duration) -> IbusConnection {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+
+    loop {
+        tokio::time::sleep(backoff).await;
+
+        // `ibus restart`の直後はアドレスが更新されていないことがあるため、毎回取得し直す
+        if let Ok(ibus_conn) = IbusConnection::open(timeout).await {
+            return ibus_conn;
+        }
+
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// ibus-daemonが再起動されても再接続しながら状態を送る。
+async fn watch_ibus(
+    mut ibus_conn: IbusConnection,
+    classifier: EngineClassifier,
+    timeout: Duration,
+    events: UnboundedSender<ImeEvent>,
+) -> Option<()> {
+    loop {
+        // 購読後に現在の状態を送る
+        if let Some(state) = ibus_conn.current_state(&classifier).await {
+            events.unbounded_send(ImeEvent::Changed(state)).ok()?;
+        }
+
+        while let Some(state) = ibus_conn.next_state(&classifier).await {
+            events.unbounded_send(ImeEvent::Changed(state)).ok()?;
+        }
+
+        events.unbounded_send(ImeEvent::BackendLost).ok()?;
+        ibus_conn = reconnect_ibus(timeout).await;
+        events.unbounded_send(ImeEvent::BackendRecovered).ok()?;
+    }
+}
+
+/// IBusの`GlobalEngineChanged`シグナルのストリーム。
+///
+/// ibus-daemonが再起動された場合はアドレスを取得し直して再接続する。
+pub async fn ibus_stream(
+    classifier: EngineClassifier,
+    settings: DbusSettings,
+) -> Result<BoxStream<'static, ImeEvent>, ImeWatchError> {
+    let ibus_conn = IbusConnection::open(settings.timeout).await?;
+
+    let stream =
+        TaskStream::spawn(|events| watch_ibus(ibus_conn, classifier, settings.timeout, events));
+
+    Ok(detect_changes(stream.boxed(), settings.emit))
+}
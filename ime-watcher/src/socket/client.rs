@@ -0,0 +1,107 @@
+//! 状態サーバーのクライアント。
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use super::protocol::{self, ProtocolError, Response};
+use crate::{ImeEvent, ImeState};
+
+/// 接続先のソケット。要求ごとに接続する
+#[derive(Debug, Clone)]
+pub struct Client {
+    path: PathBuf,
+}
+
+fn read_response(reader: &mut BufReader<UnixStream>) -> std::io::Result<Option<Response>> {
+    let mut line = String::new();
+
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Response::decode(&line)?))
+}
+
+/// `STATE`/`NONE`以外の応答をエラーにする。
+fn expect_state(response: Option<Response>) -> std::io::Result<Option<ImeState>> {
+    match response {
+        Some(Response::State(state)) => Ok(Some(state)),
+        Some(Response::None) => Ok(None),
+        Some(Response::Error(reason)) => Err(std::io::Error::other(reason)),
+        Some(response) => Err(ProtocolError::UnknownResponse(response.encode()).into()),
+        None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+impl Client {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// `$XDG_RUNTIME_DIR/ime-watcher.sock`に接続する。`XDG_RUNTIME_DIR`がない場合は`None`
+    pub fn from_env() -> Option<Self> {
+        super::default_socket_path().map(Self::new)
+    }
+
+    fn request(&self, command: &str) -> std::io::Result<BufReader<UnixStream>> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        writeln!(stream, "{command}")?;
+
+        Ok(BufReader::new(stream))
+    }
+
+    /// 現在の状態。サーバーがまだ状態を取得していない場合は`None`
+    pub fn get(&self) -> std::io::Result<Option<ImeState>> {
+        let mut reader = self.request(protocol::GET)?;
+
+        expect_state(read_response(&mut reader)?)
+    }
+
+    /// 変更を購読する。
+    pub fn subscribe(&self) -> std::io::Result<Subscription> {
+        let mut reader = self.request(protocol::SUBSCRIBE)?;
+        let current = expect_state(read_response(&mut reader)?)?;
+
+        Ok(Subscription { reader, current })
+    }
+}
+
+/// 購読中の接続。サーバーが終了するとイテレーターも終了する
+pub struct Subscription {
+    reader: BufReader<UnixStream>,
+    current: Option<ImeState>,
+}
+
+impl Subscription {
+    /// 最後に受け取った状態
+    pub fn current(&self) -> Option<&ImeState> {
+        self.current.as_ref()
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = std::io::Result<ImeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = match read_response(&mut self.reader) {
+            Ok(Some(Response::State(state))) => {
+                self.current = Some(state.clone());
+                ImeEvent::Changed(state)
+            }
+            Ok(Some(Response::BackendLost)) => ImeEvent::BackendLost,
+            Ok(Some(Response::BackendRecovered)) => ImeEvent::BackendRecovered,
+            Ok(Some(Response::GroupChanged(group))) => ImeEvent::GroupChanged(group),
+            Ok(Some(Response::Error(reason))) => return Some(Err(std::io::Error::other(reason))),
+            Ok(Some(Response::None)) => {
+                return Some(Err(ProtocolError::UnknownResponse("NONE".to_owned()).into()));
+            }
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        Some(Ok(event))
+    }
+}
@@ -0,0 +1,329 @@
+//! 1つの監視を複数のクライアントで共有するためのUnixドメインソケットのサーバー。
+//!
+//! プロトコルは[`protocol`]を参照。
+
+pub mod client;
+pub mod protocol;
+
+pub use client::{Client, Subscription};
+pub use protocol::{ProtocolError, Response};
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::{Broadcaster, ImeEvent};
+
+/// 新しい接続を確認する間隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 接続したクライアントがコマンドを送るまで待つ時間
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `$XDG_RUNTIME_DIR/ime-watcher.sock`
+pub fn default_socket_path() -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+
+    Some(PathBuf::from(runtime_dir).join("ime-watcher.sock"))
+}
+
+/// 監視のイベントをソケットのクライアントへ配信する。
+///
+/// ソケットのファイルは破棄とともに削除される。
+pub struct StateServer {
+    listener: UnixListener,
+    path: PathBuf,
+    broadcaster: Arc<Broadcaster>,
+}
+
+impl StateServer {
+    /// ソケットを作る。既にソケットが存在する場合、応答があれば`AddrInUse`、なければ古いものとして
+    /// 削除する。ソケット以外のファイルは削除せず`AlreadyExists`とする
+    pub fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+
+        let existing = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(metadata) = existing {
+            if !metadata.file_type().is_socket() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is already served", path.display()),
+                ));
+            }
+
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            broadcaster: Arc::new(Broadcaster::new()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `events`が切断されるまで配信する。監視を停止するとReceiverが切断されて戻る
+    pub fn run(&self, events: Receiver<ImeEvent>) -> std::io::Result<()> {
+        loop {
+            match events.recv_timeout(ACCEPT_INTERVAL) {
+                Ok(ImeEvent::Changed(state)) => self.broadcaster.publish_state(state),
+                Ok(event) => self.broadcaster.send(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _addr)) => {
+                        let broadcaster = self.broadcaster.clone();
+
+                        // クライアントの失敗はサーバーを止めない
+                        std::thread::spawn(move || {
+                            let _ = serve_client(stream, &broadcaster);
+                        });
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        self.broadcaster.close();
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // 購読中のクライアントとの接続を閉じる
+        self.broadcaster.close();
+
+        Ok(())
+    }
+}
+
+impl Drop for StateServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn write_response(stream: &mut UnixStream, response: Response) -> std::io::Result<()> {
+    writeln!(stream, "{}", response.encode())
+}
+
+fn current_response(broadcaster: &Broadcaster) -> Response {
+    match broadcaster.current_state() {
+        Some(state) => Response::State(state),
+        None => Response::None,
+    }
+}
+
+/// 1つのクライアントとのやりとり
+fn serve_client(mut stream: UnixStream, broadcaster: &Broadcaster) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+
+    // コマンドを送らないクライアントにスレッドを占有させない
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let mut command = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut command)?;
+    stream.set_read_timeout(None)?;
+
+    match command.trim() {
+        protocol::GET => write_response(&mut stream, current_response(broadcaster)),
+        protocol::SUBSCRIBE => {
+            // 先に購読し、取りこぼしがないようにする
+            let receiver = broadcaster.subscribe();
+            write_response(&mut stream, current_response(broadcaster))?;
+
+            // 書き込みに失敗した時点でクライアントは切断されている
+            for event in receiver {
+                write_response(&mut stream, event.into())?;
+            }
+
+            Ok(())
+        }
+        other => write_response(
+            &mut stream,
+            Response::Error(format!("unknown command `{other}`")),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImeState, InputKind};
+
+    use std::sync::mpsc::{Sender, channel};
+    use std::thread::JoinHandle;
+
+    /// バックエンドの代わりにテストからイベントを送る。
+    struct FakeBackend {
+        sender: Option<Sender<ImeEvent>>,
+        server: Option<JoinHandle<std::io::Result<()>>>,
+        path: PathBuf,
+        _dir: tempfile::TempDir,
+    }
+
+    impl FakeBackend {
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("ime-watcher.sock");
+
+            let server = StateServer::bind(&path).unwrap();
+            let (sender, receiver) = channel();
+
+            Self {
+                sender: Some(sender),
+                server: Some(std::thread::spawn(move || server.run(receiver))),
+                path,
+                _dir: dir,
+            }
+        }
+
+        fn send(&self, event: ImeEvent) {
+            self.sender.as_ref().unwrap().send(event).unwrap();
+        }
+
+        /// 監視の停止に相当する。
+        fn stop(&mut self) {
+            self.sender.take();
+            self.server.take().unwrap().join().unwrap().unwrap();
+        }
+    }
+
+    fn mozc() -> ImeState {
+        ImeState::new("mozc")
+            .with_kind(InputKind::Composing)
+            .with_language("ja")
+    }
+
+    fn keyboard_us() -> ImeState {
+        ImeState::new("keyboard-us").with_kind(InputKind::Direct)
+    }
+
+    /// サーバーが状態を受け取るまで待つ。
+    fn wait_for_state(client: &Client, expected: &ImeState) {
+        for _ in 0..50 {
+            if client.get().unwrap().as_ref() == Some(expected) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        panic!("server did not receive {expected}");
+    }
+
+    #[test]
+    fn get_returns_current_state() {
+        let mut backend = FakeBackend::start();
+        let client = Client::new(&backend.path);
+
+        assert_eq!(client.get().unwrap(), None);
+
+        backend.send(ImeEvent::Changed(mozc()));
+        wait_for_state(&client, &mozc());
+
+        backend.stop();
+        assert!(!backend.path.exists());
+    }
+
+    #[test]
+    fn subscribers_share_one_feed() {
+        let mut backend = FakeBackend::start();
+        let client = Client::new(&backend.path);
+
+        backend.send(ImeEvent::Changed(keyboard_us()));
+        wait_for_state(&client, &keyboard_us());
+
+        let mut first = client.subscribe().unwrap();
+        let mut second = client.subscribe().unwrap();
+
+        assert_eq!(first.current(), Some(&keyboard_us()));
+        assert_eq!(second.current(), Some(&keyboard_us()));
+
+        backend.send(ImeEvent::BackendLost);
+        backend.send(ImeEvent::BackendRecovered);
+        backend.send(ImeEvent::Changed(mozc()));
+
+        for subscription in [&mut first, &mut second] {
+            assert_eq!(subscription.next().unwrap().unwrap(), ImeEvent::BackendLost);
+            assert_eq!(
+                subscription.next().unwrap().unwrap(),
+                ImeEvent::BackendRecovered
+            );
+            assert_eq!(
+                subscription.next().unwrap().unwrap(),
+                ImeEvent::Changed(mozc())
+            );
+        }
+
+        // 監視が停止すると購読も終了する
+        backend.stop();
+        assert!(first.next().is_none());
+        assert!(second.next().is_none());
+    }
+
+    #[test]
+    fn second_server_on_same_path_is_rejected() {
+        let mut backend = FakeBackend::start();
+
+        let res = StateServer::bind(&backend.path);
+        assert_eq!(
+            res.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+
+        backend.stop();
+
+        // 異常終了で残ったソケットは作り直せる
+        drop(UnixListener::bind(&backend.path).unwrap());
+        assert!(StateServer::bind(&backend.path).is_ok());
+    }
+
+    #[test]
+    fn silent_client_is_dropped() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+
+        let res = serve_client(server, &Broadcaster::new());
+        assert!(res.is_err());
+
+        // サーバー側は閉じられている
+        let mut rest = String::new();
+        BufReader::new(&mut client).read_line(&mut rest).unwrap();
+        assert_eq!(rest, "");
+    }
+
+    #[test]
+    fn regular_file_on_path_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ime-watcher.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let res = StateServer::bind(&path);
+        assert_eq!(
+            res.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+}
@@ -0,0 +1,234 @@
+//! 状態サーバーの行ベースのプロトコル。
+//!
+//! クライアントは`GET`または`SUBSCRIBE`を1行送る。サーバーは`GET`には現在の状態を1行返して
+//! 接続を閉じ、`SUBSCRIBE`には現在の状態に続けて変更のたびに1行ずつ送る。
+//!
+//! ```text
+//! STATE <engine>\t<kind>\t<language>\t<open>\t<display_name>\t<symbol>
+//! NONE
+//! LOST
+//! RECOVERED
+//! GROUP <group>
+//! ERROR <reason>
+//! ```
+//!
+//! 値の`\`・タブ・改行は`\\`, `\t`, `\n`とエスケープし、`None`は空文字列とする。
+//! `open`は`open`, `closed`または空文字列。
+
+use crate::{ImeEvent, ImeState};
+
+pub const GET: &str = "GET";
+pub const SUBSCRIBE: &str = "SUBSCRIBE";
+
+/// サーバーから送られる1行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// 現在の状態、または変更後の状態
+    State(ImeState),
+    /// まだ状態を取得していない
+    None,
+    BackendLost,
+    BackendRecovered,
+    /// 入力メソッドのグループ名
+    GroupChanged(String),
+    Error(String),
+}
+
+impl From<ImeEvent> for Response {
+    fn from(event: ImeEvent) -> Self {
+        match event {
+            ImeEvent::Changed(state) => Response::State(state),
+            ImeEvent::BackendLost => Response::BackendLost,
+            ImeEvent::BackendRecovered => Response::BackendRecovered,
+            ImeEvent::GroupChanged(group) => Response::GroupChanged(group),
+        }
+    }
+}
+
+/// 受け取った行が読めない理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnknownResponse(String),
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnknownResponse(line) => write!(f, "unknown response `{line}`"),
+            ProtocolError::MissingField(field) => write!(f, "missing field `{field}`"),
+            ProtocolError::InvalidField(field) => write!(f, "invalid field `{field}`"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for std::io::Error {
+    fn from(e: ProtocolError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+fn optional(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| unescape(value))
+}
+
+impl Response {
+    /// 改行を含まない1行にする。
+    pub fn encode(&self) -> String {
+        match self {
+            Response::State(state) => {
+                let open = match state.open {
+                    Some(true) => "open",
+                    Some(false) => "closed",
+                    None => "",
+                };
+
+                let fields = [
+                    escape(&state.engine),
+                    state.kind.to_string(),
+                    escape(state.language.as_deref().unwrap_or_default()),
+                    open.to_owned(),
+                    escape(state.display_name.as_deref().unwrap_or_default()),
+                    escape(state.symbol.as_deref().unwrap_or_default()),
+                ];
+
+                format!("STATE {}", fields.join("\t"))
+            }
+            Response::None => "NONE".to_owned(),
+            Response::BackendLost => "LOST".to_owned(),
+            Response::BackendRecovered => "RECOVERED".to_owned(),
+            Response::GroupChanged(group) => format!("GROUP {}", escape(group)),
+            Response::Error(reason) => format!("ERROR {}", escape(reason)),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Self, ProtocolError> {
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        match line.split_once(' ') {
+            Some(("STATE", fields)) => {
+                let mut fields = fields.split('\t');
+                let mut field = |name| fields.next().ok_or(ProtocolError::MissingField(name));
+
+                let engine = unescape(field("engine")?);
+                let kind = field("kind")?
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidField("kind"))?;
+                let language = optional(field("language")?);
+                let open = match field("open")? {
+                    "open" => Some(true),
+                    "closed" => Some(false),
+                    "" => None,
+                    _ => return Err(ProtocolError::InvalidField("open")),
+                };
+                let display_name = optional(field("display_name")?);
+                let symbol = optional(field("symbol")?);
+
+                Ok(Response::State(ImeState {
+                    engine,
+                    display_name,
+                    language,
+                    kind,
+                    symbol,
+                    open,
+                }))
+            }
+            Some(("GROUP", group)) => Ok(Response::GroupChanged(unescape(group))),
+            Some(("ERROR", reason)) => Ok(Response::Error(unescape(reason))),
+            None if line == "NONE" => Ok(Response::None),
+            None if line == "LOST" => Ok(Response::BackendLost),
+            None if line == "RECOVERED" => Ok(Response::BackendRecovered),
+            _ => Err(ProtocolError::UnknownResponse(line.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputKind;
+
+    #[test]
+    fn state_round_trip() {
+        let state = ImeState::new("mozc\tjp")
+            .with_kind(InputKind::Composing)
+            .with_language("ja")
+            .with_display_name("Mozc\\")
+            .with_symbol("あ")
+            .with_open(false);
+
+        let line = Response::State(state.clone()).encode();
+        assert!(!line.contains('\n'));
+        assert_eq!(Response::decode(&line), Ok(Response::State(state)));
+
+        let state = ImeState::new("keyboard-us").with_kind(InputKind::Direct);
+        assert_eq!(
+            Response::State(state.clone()).encode(),
+            "STATE keyboard-us\tdirect\t\t\t\t"
+        );
+        assert_eq!(
+            Response::decode("STATE keyboard-us\tdirect\t\t\t\t\n"),
+            Ok(Response::State(state))
+        );
+
+        let group = Response::GroupChanged("Group 2".to_owned());
+        assert_eq!(Response::decode(&group.encode()), Ok(group));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            Response::decode("STATE mozc\tcomposing"),
+            Err(ProtocolError::MissingField("language"))
+        );
+        assert_eq!(
+            Response::decode("STATE mozc\tfoo\t\t\t\t"),
+            Err(ProtocolError::InvalidField("kind"))
+        );
+        assert!(matches!(
+            Response::decode("HELLO"),
+            Err(ProtocolError::UnknownResponse(_))
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Deserialize;
+
+/// 入力メソッドの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputKind {
+    /// キーボードレイアウトによる直接入力
+    Direct,
+    /// 変換を伴うIME
+    Composing,
+    #[default]
+    Unknown,
+}
+
+impl std::fmt::Display for InputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputKind::Direct => write!(f, "direct"),
+            InputKind::Composing => write!(f, "composing"),
+            InputKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl std::str::FromStr for InputKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(InputKind::Direct),
+            "composing" => Ok(InputKind::Composing),
+            "unknown" => Ok(InputKind::Unknown),
+            other => Err(format!("unknown input kind `{other}`")),
+        }
+    }
+}
+
+/// 各バックエンドが共通して返すIMEの状態。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImeState {
+    /// バックエンド固有のエンジンのID(`mozc`, `xkb:us::eng`など)
+    pub engine: String,
+    /// 表示名
+    pub display_name: Option<String>,
+    /// 言語タグ(`ja`, `en-US`など)
+    pub language: Option<String>,
+    pub kind: InputKind,
+    /// パネルなどに表示する短い記号(`あ`, `en`など)
+    pub symbol: Option<String>,
+    /// IMEのオープン状態。取得できない場合は`None`
+    pub open: Option<bool>,
+}
+
+impl ImeState {
+    pub fn new(engine: impl Into<String>) -> Self {
+        Self {
+            engine: engine.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_kind(mut self, kind: InputKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_display_name(mut self, display_name: impl Into<String>) -> Self {
+        self.display_name = Some(display_name.into());
+        self
+    }
+
+    pub fn with_language(mut self, language: impl Into<String>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    pub fn with_symbol(mut self, symbol: impl Into<String>) -> Self {
+        self.symbol = Some(symbol.into());
+        self
+    }
+
+    pub fn with_open(mut self, open: bool) -> Self {
+        self.open = Some(open);
+        self
+    }
+
+    /// IMEが有効(変換入力中)かどうか。オープン状態が分かる場合はそちらを優先する。
+    pub fn is_active(&self) -> bool {
+        match self.kind {
+            InputKind::Direct => false,
+            InputKind::Composing | InputKind::Unknown => {
+                self.open.unwrap_or(self.kind == InputKind::Composing)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ImeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}", self.engine, self.kind)?;
+
+        if let Some(language) = &self.language {
+            write!(f, ", {language}")?;
+        }
+
+        match self.open {
+            Some(true) => write!(f, ", open")?,
+            Some(false) => write!(f, ", closed")?,
+            None => {}
+        }
+
+        write!(f, ")")
+    }
+}
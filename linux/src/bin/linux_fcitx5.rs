@@ -1,89 +1,42 @@
-use dbus::blocking::{SyncConnection, stdintf::org_freedesktop_dbus::Properties};
-use dbus::message::MatchRule;
-
-use std::sync::mpsc::sync_channel;
-use std::time::Duration;
-
-fn main() -> Result<(), dbus::Error> {
-    let conn = SyncConnection::new_session()?;
-
-    let notifier_watcher_proxy = conn.with_proxy(
-        "org.kde.StatusNotifierWatcher",
-        "/StatusNotifierWatcher",
-        Duration::from_millis(500),
-    );
-
-    let notifier_items: Vec<String> = notifier_watcher_proxy.get(
-        "org.kde.StatusNotifierWatcher",
-        "RegisteredStatusNotifierItems",
-    )?;
-
-    let fcitx5_sni_proxy = {
-        let mut fcitx5_sni_proxy = None;
-
-        for sni_name in notifier_items.into_iter() {
-            let (dest, path) = {
-                let (dest, path) = sni_name.split_once("@").unwrap();
-                (dest.to_owned(), path.to_owned())
-            };
-
-            let sni_proxy = conn.with_proxy(dest, path, Duration::from_millis(500));
-            let sni_id: String = sni_proxy.get("org.kde.StatusNotifierItem", "Id")?;
-
-            if sni_id.as_str() == "Fcitx" {
-                fcitx5_sni_proxy = Some(sni_proxy);
-            }
-        }
-
-        fcitx5_sni_proxy
+use ime_watcher::{
+    Config, EngineClassifier, ImeEvent, ImeWatcher,
+    linux::{DbusSettings, Fcitx5Watcher, fcitx5::Fcitx5Mode},
+};
+
+/// `linux_fcitx5 [auto|native|sni]`
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mode = match std::env::args().nth(1).as_deref() {
+        Some("native") => Fcitx5Mode::Native,
+        Some("sni") => Fcitx5Mode::StatusNotifier,
+        _ => Fcitx5Mode::Auto,
     };
 
-    let Some(fcitx5_sni_proxy) = fcitx5_sni_proxy else {
-        return Ok(()); // 実際にはエラーとする
-    };
-
-    let signal_mr = MatchRule::new_signal("org.kde.StatusNotifierItem", "NewIcon");
-
-    // タイミングの通知用
-    struct GetInputMethod;
-
-    let (sender, receiver) = sync_channel(1);
-
-    let _token = fcitx5_sni_proxy.match_start(
-        signal_mr,
-        true,
-        Box::new(move |_message, _| {
-            let _ = sender.try_send(GetInputMethod);
+    let config = Config::load(None, Default::default())?;
 
-            true
-        }),
-    )?;
+    let mut watcher = Fcitx5Watcher::new()
+        .with_classifier(EngineClassifier::from_user_config()?)
+        .with_mode(mode)
+        .with_settings(DbusSettings::from(&config));
 
-    std::thread::spawn({
-        let worker_conn = SyncConnection::new_session()?;
+    let receiver = watcher.subscribe();
 
-        move || -> Result<(), dbus::Error> {
-            let controller_proxy = worker_conn.with_proxy(
-                "org.fcitx.Fcitx5",
-                "/controller",
-                Duration::from_millis(500),
-            );
+    // SIGINT/SIGTERMで停止する
+    let stop_handle = watcher.stop_handle();
+    ctrlc::set_handler(move || stop_handle.stop())?;
 
-            while let Ok(_msg) = receiver.recv() {
-                let (ime_status,): (String,) = controller_proxy.method_call(
-                    "org.fcitx.Fcitx.Controller1",
-                    "CurrentInputMethod",
-                    (),
-                )?;
+    watcher.start()?;
 
-                println!("ime_status: {ime_status}");
-            }
-
-            Ok(())
+    // 監視が終了するまで受け取る
+    for event in receiver {
+        match event {
+            ImeEvent::Changed(state) => println!("ime_status: {state}"),
+            ImeEvent::BackendLost => println!("backend lost"),
+            ImeEvent::BackendRecovered => println!("backend recovered"),
+            ImeEvent::GroupChanged(group) => println!("group changed: {group}"),
         }
-    });
-
-    loop {
-        conn.process(Duration::from_millis(1000))?;
     }
+
+    watcher.stop()?;
+
+    Ok(())
 }
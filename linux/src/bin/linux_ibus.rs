@@ -1,60 +1,33 @@
-use dbus::{blocking::Connection, channel::Channel, message::MatchRule};
-
-// use dbus::arg::{RefArg, Variant};
+use ime_watcher::{
+    Config, EngineClassifier, ImeEvent, ImeWatcher,
+    linux::{DbusSettings, IbusWatcher},
+};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use std::process::Command;
-
-    let cmd_out = Command::new("ibus").arg("address").output()?;
-
-    let address = String::from_utf8(cmd_out.stdout)?.trim_end().to_string();
-
-    let conn: Connection = Channel::open_private(&address)?.into();
-
-    let proxy = conn.with_proxy(
-        "org.freedesktop.IBus",
-        "/org/freedesktop/IBus",
-        std::time::Duration::from_millis(500),
-    );
+    let config = Config::load(None, Default::default())?;
 
-    // let (res,): (Variant<Box<dyn RefArg>>,) =
-    //     proxy.method_call("org.freedesktop.IBus", "GetGlobalEngine", ())?;
+    let mut watcher = IbusWatcher::new()
+        .with_classifier(EngineClassifier::from_user_config()?)
+        .with_settings(DbusSettings::from(&config));
 
-    // println!("{:?}", res.0);
+    let receiver = watcher.subscribe();
 
-    // let signature = res.0.signature();
-    // println!("base signature: {signature}");
+    // SIGINT/SIGTERMで停止する
+    let stop_handle = watcher.stop_handle();
+    ctrlc::set_handler(move || stop_handle.stop())?;
 
-    // for i in res.as_iter().unwrap() {
-    //     println!("{i:?}");
-    // }
+    watcher.start()?;
 
-    // let ime_status = res
-    //     .0
-    //     .as_iter()
-    //     .unwrap()
-    //     .nth(2)
-    //     .unwrap()
-    //     .as_str()
-    //     .unwrap()
-    //     .to_owned();
-
-    // println!("ime_status: {ime_status}");
-
-    let signal_mr = MatchRule::new_signal("org.freedesktop.IBus", "GlobalEngineChanged");
-
-    let _token = proxy.match_start(
-        signal_mr,
-        true,
-        Box::new(|message, _| {
-            let engine_name: String = message.read1().unwrap();
-            println!("{engine_name}");
+    for event in receiver {
+        match event {
+            ImeEvent::Changed(state) => println!("{state}"),
+            ImeEvent::BackendLost => println!("backend lost"),
+            ImeEvent::BackendRecovered => println!("backend recovered"),
+            ImeEvent::GroupChanged(group) => println!("group changed: {group}"),
+        }
+    }
 
-            true
-        }),
-    );
+    watcher.stop()?;
 
-    loop {
-        conn.process(std::time::Duration::from_millis(1000))?;
-    }
+    Ok(())
 }
@@ -0,0 +1,73 @@
+use futures_util::StreamExt;
+use ime_watcher::{
+    Config, EngineClassifier, ImeEvent,
+    config::{BackendChoice, ConfigLayer},
+    linux::{
+        Backend, DbusSettings,
+        detect::Evidence,
+        stream::{fcitx5_stream, ibus_stream},
+    },
+};
+use tokio::signal::unix::{SignalKind, signal};
+
+/// `linux_tokio [--backend auto|fcitx5|ibus]`
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let classifier = EngineClassifier::from_user_config()?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let backend = match args.as_slice() {
+        [] => None,
+        [flag, backend] if flag == "--backend" => Some(backend.parse()?),
+        _ => return Err("usage: linux_tokio [--backend auto|fcitx5|ibus]".into()),
+    };
+
+    // 設定ファイルと環境変数の指定を`--backend`で上書きする
+    let config = Config::load(
+        None,
+        ConfigLayer {
+            backend,
+            ..Default::default()
+        },
+    )?;
+    let settings = DbusSettings::from(&config);
+
+    let backend = match config.backend {
+        BackendChoice::Auto => {
+            let detection = Evidence::collect(&settings).decide()?;
+            eprintln!("detected backend: {detection}");
+            detection.backend
+        }
+        BackendChoice::Fcitx5 => Backend::Fcitx5,
+        BackendChoice::Ibus => Backend::Ibus,
+    };
+
+    let mut stream = match backend {
+        Backend::Fcitx5 => fcitx5_stream(classifier, settings).await?,
+        Backend::Ibus => ibus_stream(classifier, settings).await?,
+    };
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            _ = tokio::signal::ctrl_c() => None,
+            _ = sigterm.recv() => None,
+        };
+
+        // ストリームを破棄すると接続も閉じられる
+        let Some(event) = event else {
+            break;
+        };
+
+        match event {
+            ImeEvent::Changed(state) => println!("ime_status: {state}"),
+            ImeEvent::BackendLost => println!("backend lost"),
+            ImeEvent::BackendRecovered => println!("backend recovered"),
+            ImeEvent::GroupChanged(group) => println!("group changed: {group}"),
+        }
+    }
+
+    Ok(())
+}
@@ -13,6 +13,8 @@ use core_foundation_sys::notification_center::{
     CFNotificationCenterRef, CFNotificationCenterRemoveObserver, CFNotificationName,
     CFNotificationSuspensionBehavior,
 };
+use ime_watcher::debounce::{DebounceSettings, Debouncer, Edge};
+use ime_watcher::{Config, ImeState, InputKind};
 use once_cell::sync::OnceCell;
 
 static GET_IME_MESSAGE_SENDER: OnceCell<SyncSender<GetKeyboardInputSourceNotification>> =
@@ -64,7 +66,18 @@ impl std::fmt::Display for MacError {
 
 impl std::error::Error for MacError {}
 
-fn get_current_input_source() -> Result<String, MacError> {
+/// 入力ソースのIDから種類を判定する。
+fn input_source_kind(input_source: &str) -> InputKind {
+    if input_source.starts_with("com.apple.keylayout.") {
+        InputKind::Direct
+    } else if input_source.starts_with("com.apple.inputmethod.") {
+        InputKind::Composing
+    } else {
+        InputKind::Unknown
+    }
+}
+
+fn get_current_input_source() -> Result<ImeState, MacError> {
     unsafe {
         let source = TISCopyCurrentKeyboardInputSource();
 
@@ -78,7 +91,9 @@ fn get_current_input_source() -> Result<String, MacError> {
 
         CFRelease(source);
 
-        Ok(input_source)
+        let kind = input_source_kind(&input_source);
+
+        Ok(ImeState::new(input_source).with_kind(kind))
     }
 }
 
@@ -118,18 +133,31 @@ fn run_loop() -> Result<(), MacError> {
     Ok(())
 }
 
+/// 通知が途切れてから入力ソースを取得するまでの既定の時間
+const NOTIFY_DELAY: Duration = Duration::from_millis(40);
+
 fn main() -> Result<(), MacError> {
     use std::sync::mpsc::sync_channel;
 
+    let notify_delay = Config::load(None, Default::default())
+        .map_err(|e| MacError(e.to_string()))?
+        .notify_delay
+        .unwrap_or(NOTIFY_DELAY);
+
     let (message_sender, message_receiver) = sync_channel(1);
 
     let _ = GET_IME_MESSAGE_SENDER.set(message_sender);
 
-    let mut pre_ime_status = "".to_string();
+    let mut pre_ime_status = ImeState::default();
+
+    let debouncer = Debouncer::new(DebounceSettings {
+        edge: Edge::Trailing,
+        quiet: notify_delay,
+        ..Default::default()
+    });
 
     std::thread::spawn(move || {
-        while let Ok(_m) = message_receiver.recv() {
-            std::thread::sleep(Duration::from_millis(40));
+        debouncer.run(&message_receiver, || {
             if let Ok(ime_status) = get_current_input_source() {
                 //
                 if pre_ime_status != ime_status {
@@ -137,7 +165,7 @@ fn main() -> Result<(), MacError> {
                     pre_ime_status = ime_status;
                 }
             }
-        }
+        });
     });
 
     // 必ずメインスレッドとする。
@@ -16,6 +16,8 @@ use windows::Win32::{
 };
 use windows::core::{Error as WinError, w};
 
+use ime_watcher::debounce::{DebounceSettings, Debouncer, Edge};
+use ime_watcher::{Config, ImeState};
 use once_cell::sync::OnceCell;
 
 static GET_KEYBOARD_LAYOUT_SENDER: OnceCell<SyncSender<GetKeyboardLayoutNotification>> =
@@ -159,7 +161,7 @@ fn initialize_locale_map() -> Result<HashMap<u16, String>, GetKeyboardLayoutErro
 /// ループの中で呼ぶ。
 fn get_keyboard_layout(
     locale_map: &HashMap<u16, String>,
-) -> Result<String, GetKeyboardLayoutError> {
+) -> Result<ImeState, GetKeyboardLayoutError> {
     unsafe {
         let foreground_hwnd = GetForegroundWindow();
 
@@ -191,7 +193,9 @@ fn get_keyboard_layout(
         }
 
         match locale_map.get(&((hkl.0 as usize & 0xFFFF) as u16)) {
-            Some(locale) => Ok(locale.to_owned()),
+            Some(locale) => {
+                Ok(ImeState::new(format!("{:08x}", hkl.0 as usize)).with_language(locale))
+            }
             None => Err(GetKeyboardLayoutError),
         }
     }
@@ -267,28 +271,35 @@ pub fn ui_loop() -> Result<(), WinError> {
     Ok(())
 }
 
+/// 通知が途切れてから状態を取得するまでの既定の時間
+const NOTIFY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let notify_delay = Config::load(None, Default::default())?
+        .notify_delay
+        .unwrap_or(NOTIFY_DELAY);
+
     let (sender, receiver) = sync_channel(1);
 
     GET_KEYBOARD_LAYOUT_SENDER.set(sender).unwrap();
 
     let locale_map = initialize_locale_map()?;
 
-    std::thread::spawn(move || -> Result<(), GetKeyboardLayoutError> {
-        while let Ok(_msg) = receiver.recv() {
-            std::thread::sleep(std::time::Duration::from_millis(50));
+    let debouncer = Debouncer::new(DebounceSettings {
+        edge: Edge::Trailing,
+        quiet: notify_delay,
+        ..Default::default()
+    });
 
-            match get_keyboard_layout(&locale_map) {
-                Ok(keyboard_layout) => {
-                    println!("keyboard_layout: {keyboard_layout}");
-                }
-                Err(e) => {
-                    println!("{e}"); // コンソールアプリなどではこちらになることがある。
-                }
+    std::thread::spawn(move || {
+        debouncer.run(&receiver, || match get_keyboard_layout(&locale_map) {
+            Ok(keyboard_layout) => {
+                println!("keyboard_layout: {keyboard_layout}");
             }
-        }
-
-        Ok(())
+            Err(e) => {
+                eprintln!("{e}"); // コンソールアプリなどではこちらになることがある。
+            }
+        });
     });
 
     ui_loop()?;
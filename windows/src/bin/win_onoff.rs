@@ -6,13 +6,15 @@ use windows::Win32::{
     System::LibraryLoader::GetModuleHandleW,
     UI::{
         Accessibility::*,
-        Input::{Ime::ImmGetDefaultIMEWnd, *},
+        Input::{Ime::ImmGetDefaultIMEWnd, KeyboardAndMouse::GetKeyboardLayout, *},
         WindowsAndMessaging::*,
     },
 };
 
 use windows::core::{Error as WinError, w};
 
+use ime_watcher::debounce::{DebounceSettings, Debouncer, Edge};
+use ime_watcher::{Config, ImeState};
 use once_cell::sync::OnceCell;
 
 static GET_OPEN_STATUS_SENDER: OnceCell<SyncSender<GetOpenStatusNotification>> = OnceCell::new();
@@ -56,7 +58,7 @@ impl std::fmt::Display for GetOpenStatusError {
 impl std::error::Error for GetOpenStatusError {}
 
 // SendMessageを行うため、必ずUIスレッド、フックなどとは異なるスレッドから呼ぶ。
-fn get_open_status() -> Result<String, GetOpenStatusError> {
+fn get_open_status() -> Result<ImeState, GetOpenStatusError> {
     unsafe {
         let foreground_hwnd = GetForegroundWindow();
 
@@ -106,11 +108,10 @@ fn get_open_status() -> Result<String, GetOpenStatusError> {
             result
         };
 
-        if result == 0 {
-            Ok("ime-off".to_string())
-        } else {
-            Ok("ime-on".to_string())
-        }
+        // エンジンのIDとしてキーボードレイアウトのハンドルを用いる
+        let hkl = GetKeyboardLayout(GetWindowThreadProcessId(target_hwnd, None));
+
+        Ok(ImeState::new(format!("{:08x}", hkl.0 as usize)).with_open(result != 0))
     }
 }
 
@@ -234,21 +235,29 @@ pub fn ui_loop() -> Result<(), WinError> {
     Ok(())
 }
 
+/// 通知が途切れてから状態を取得するまでの既定の時間
+const NOTIFY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let notify_delay = Config::load(None, Default::default())?
+        .notify_delay
+        .unwrap_or(NOTIFY_DELAY);
+
     let (sender, receiver) = sync_channel(1);
 
     GET_OPEN_STATUS_SENDER.set(sender).unwrap();
 
-    std::thread::spawn(move || -> Result<(), GetOpenStatusError> {
-        while let Ok(_msg) = receiver.recv() {
-            std::thread::sleep(std::time::Duration::from_millis(50));
-
-            let open_status = get_open_status()?;
-
-            println!("ime_open_status: {open_status}");
-        }
+    let debouncer = Debouncer::new(DebounceSettings {
+        edge: Edge::Trailing,
+        quiet: notify_delay,
+        ..Default::default()
+    });
 
-        Ok(())
+    std::thread::spawn(move || {
+        debouncer.run(&receiver, || match get_open_status() {
+            Ok(open_status) => println!("ime_open_status: {open_status}"),
+            Err(e) => eprintln!("{e}"),
+        });
     });
 
     ui_loop()?;